@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX IF EXISTS public.request_search_idx;
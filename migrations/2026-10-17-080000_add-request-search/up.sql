@@ -0,0 +1,6 @@
+-- Full-text search over request names, descriptions and notes
+CREATE INDEX request_search_idx ON public.request USING GIN ((
+    setweight(to_tsvector('english', name), 'A') ||
+    setweight(to_tsvector('english', coalesce(description, '')), 'B') ||
+    setweight(to_tsvector('english', coalesce(note, '')), 'C')
+));
@@ -0,0 +1,12 @@
+-- Audit log of changes made to requests
+CREATE TABLE public."request_event"(
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    request_id bigint NOT NULL REFERENCES "request"(id) ON DELETE CASCADE,
+    user_id bigint REFERENCES "user"(id),
+    field text NOT NULL,
+    old_value text,
+    new_value text,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX request_event_request_id_idx ON public."request_event" (request_id);
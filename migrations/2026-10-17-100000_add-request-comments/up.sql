@@ -0,0 +1,12 @@
+-- Discussion threads on requests
+CREATE TABLE public."request_comment"(
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    request_id bigint NOT NULL REFERENCES "request"(id) ON DELETE CASCADE,
+    author_id bigint NOT NULL REFERENCES "user"(id),
+    body text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    edited_at timestamptz,
+    deleted bool NOT NULL DEFAULT false
+);
+
+CREATE INDEX request_comment_request_id_idx ON public."request_comment" (request_id);
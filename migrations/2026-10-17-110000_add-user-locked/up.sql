@@ -0,0 +1,2 @@
+-- Locked users can neither log in nor use their existing sessions and tokens
+ALTER TABLE public."user" ADD COLUMN locked bool NOT NULL DEFAULT false;
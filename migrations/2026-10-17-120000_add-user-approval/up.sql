@@ -0,0 +1,2 @@
+-- Self-registered users wait for an admin to approve them
+ALTER TABLE public."user" ADD COLUMN approved bool NOT NULL DEFAULT true;
@@ -0,0 +1,2 @@
+-- Providers are configurable now, so any name can be stored as the type
+ALTER TABLE public.oauth DROP CONSTRAINT IF EXISTS oauthtype;
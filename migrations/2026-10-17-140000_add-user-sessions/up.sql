@@ -0,0 +1,11 @@
+-- Logins to the web pages, referenced by the identity cookie
+CREATE TABLE public."user_session"(
+    id text PRIMARY KEY, -- hex-encoded SHA-256 of the token in the cookie
+    uid bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    user_agent text,
+    ip text,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX user_session_uid_idx ON public."user_session" (uid);
@@ -0,0 +1,12 @@
+-- Personal access tokens for the REST API
+CREATE TABLE public."api_token"(
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    uid bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    name text NOT NULL,
+    token_hash text NOT NULL UNIQUE, -- hex-encoded SHA-256 of the token
+    scopes text[] NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used timestamptz
+);
+
+CREATE INDEX api_token_uid_idx ON public."api_token" (uid);
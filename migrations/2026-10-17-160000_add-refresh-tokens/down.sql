@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE public."revoked_jwt";
+DROP TABLE public."refresh_token";
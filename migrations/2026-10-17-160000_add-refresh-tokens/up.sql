@@ -0,0 +1,17 @@
+-- Refresh tokens of the REST API, each paired with the JWT issued along with it
+CREATE TABLE public."refresh_token"(
+    id text PRIMARY KEY, -- hex-encoded SHA-256 of the token
+    uid bigint NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    jti text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX refresh_token_uid_idx ON public."refresh_token" (uid);
+CREATE INDEX refresh_token_jti_idx ON public."refresh_token" (jti);
+
+-- JWTs revoked before they expire
+CREATE TABLE public."revoked_jwt"(
+    jti text PRIMARY KEY,
+    expires_at timestamptz NOT NULL
+);
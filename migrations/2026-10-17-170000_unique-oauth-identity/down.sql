@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE public.oauth DROP CONSTRAINT IF EXISTS oauth_type_oid_key;
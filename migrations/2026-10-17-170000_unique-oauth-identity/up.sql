@@ -0,0 +1,2 @@
+-- An external identity can only be linked to one user
+ALTER TABLE public.oauth ADD CONSTRAINT oauth_type_oid_key UNIQUE (type, oid);
@@ -0,0 +1,231 @@
+//! Request actions shared by the web pages and the RESTful APIs
+use crate::db;
+use crate::models::{CommentInput, Request, RequestPatch, User};
+use sqlx::PgPool;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ActionError {
+    NotFound(&'static str),
+    Forbidden(&'static str),
+    Conflict(&'static str),
+    Invalid(String),
+    Internal(anyhow::Error),
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::NotFound(msg)
+            | ActionError::Forbidden(msg)
+            | ActionError::Conflict(msg) => {
+                write!(f, "{}", msg)
+            }
+            ActionError::Invalid(msg) => write!(f, "{}", msg),
+            ActionError::Internal(_) => write!(f, "Internal error"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ActionError {
+    fn from(err: anyhow::Error) -> Self {
+        ActionError::Internal(err)
+    }
+}
+
+#[inline]
+fn closed_message(status: &str) -> &'static str {
+    match status {
+        "DONE" => "Request has already been marked as done",
+        "REJECTED" => "Request has already been rejected",
+        _ => "Request is already closed",
+    }
+}
+
+async fn find_request(conn: &PgPool, id: i64) -> Result<Request, ActionError> {
+    db::get_request_by_id(conn, id)
+        .await?
+        .ok_or(ActionError::NotFound("Request not found"))
+}
+
+pub async fn claim_request(conn: &PgPool, user: &User, id: i64) -> Result<(), ActionError> {
+    let request = find_request(conn, id).await?;
+    if request.status != "OPEN" {
+        return Err(ActionError::Conflict("Only open requests can be claimed"));
+    }
+    match request.packager_id {
+        Some(packager_id) if packager_id == user.id => {
+            return Err(ActionError::Conflict(
+                "You have already claimed this request",
+            ));
+        }
+        Some(_) => {
+            return Err(ActionError::Conflict(
+                "Request is already claimed by someone else",
+            ));
+        }
+        None => (),
+    }
+    if !db::update_request_packager(conn, id, None, Some(user.id), user.id).await? {
+        return Err(ActionError::Conflict(
+            "Request has been changed by someone else",
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn unclaim_request(conn: &PgPool, user: &User, id: i64) -> Result<(), ActionError> {
+    let request = find_request(conn, id).await?;
+    let packager_id = request
+        .packager_id
+        .ok_or(ActionError::Conflict("Request is not claimed by anyone"))?;
+    if packager_id != user.id && !user.admin {
+        return Err(ActionError::Forbidden(
+            "Only the claiming packager or an admin can release this request",
+        ));
+    }
+    if request.status != "OPEN" {
+        return Err(ActionError::Conflict(closed_message(&request.status)));
+    }
+    if !db::update_request_packager(conn, id, Some(packager_id), None, user.id).await? {
+        return Err(ActionError::Conflict(
+            "Request has been changed by someone else",
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn assign_request(
+    conn: &PgPool,
+    user: &User,
+    id: i64,
+    packager: &str,
+) -> Result<(), ActionError> {
+    if !user.admin {
+        return Err(ActionError::Forbidden("Only admins can reassign requests"));
+    }
+    let request = find_request(conn, id).await?;
+    if request.status != "OPEN" {
+        return Err(ActionError::Conflict(closed_message(&request.status)));
+    }
+    let packager = db::get_user_by_username(conn, packager)
+        .await
+        .map_err(|_| ActionError::Invalid(format!("User {} does not exist", packager)))?;
+    let updated =
+        db::update_request_packager(conn, id, request.packager_id, Some(packager.id), user.id)
+            .await?;
+    if !updated {
+        return Err(ActionError::Conflict(
+            "Request has been changed by someone else",
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn close_request(
+    conn: &PgPool,
+    user: &User,
+    id: i64,
+    reject: bool,
+) -> Result<(), ActionError> {
+    let request = find_request(conn, id).await?;
+    if request.packager_id != Some(user.id) && !user.admin {
+        return Err(ActionError::Forbidden(
+            "Only the claiming packager or an admin can close this request",
+        ));
+    }
+    if request.status != "OPEN" {
+        return Err(ActionError::Conflict(closed_message(&request.status)));
+    }
+    if !db::close_request_by_id(conn, id, reject, user.id).await? {
+        return Err(ActionError::Conflict(
+            "Request has been closed by someone else",
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn add_comment(
+    conn: &PgPool,
+    user: &User,
+    request_id: i64,
+    input: &CommentInput,
+) -> Result<i64, ActionError> {
+    input
+        .validate()
+        .map_err(|msg| ActionError::Invalid(msg.to_string()))?;
+    find_request(conn, request_id).await?;
+
+    Ok(db::add_comment(conn, request_id, user.id, &input.body).await?)
+}
+
+pub async fn edit_comment(
+    conn: &PgPool,
+    user: &User,
+    request_id: i64,
+    comment_id: i64,
+    input: &CommentInput,
+) -> Result<(), ActionError> {
+    input
+        .validate()
+        .map_err(|msg| ActionError::Invalid(msg.to_string()))?;
+    let comment = db::get_comment_by_id(conn, request_id, comment_id)
+        .await?
+        .ok_or(ActionError::NotFound("Comment not found"))?;
+    if comment.author_id != user.id {
+        return Err(ActionError::Forbidden(
+            "Only the author can edit this comment",
+        ));
+    }
+    db::update_comment(conn, request_id, comment_id, &input.body, user.id).await?;
+
+    Ok(())
+}
+
+pub async fn delete_comment(
+    conn: &PgPool,
+    user: &User,
+    request_id: i64,
+    comment_id: i64,
+) -> Result<(), ActionError> {
+    let comment = db::get_comment_by_id(conn, request_id, comment_id)
+        .await?
+        .ok_or(ActionError::NotFound("Comment not found"))?;
+    if comment.author_id != user.id && !user.admin {
+        return Err(ActionError::Forbidden(
+            "Only the author or an admin can delete this comment",
+        ));
+    }
+    db::delete_comment(conn, request_id, comment_id, user.id).await?;
+
+    Ok(())
+}
+
+pub async fn edit_request(
+    conn: &PgPool,
+    user: &User,
+    id: i64,
+    patch: &RequestPatch,
+) -> Result<(), ActionError> {
+    let request = find_request(conn, id).await?;
+    if request.requester_id != user.id && request.packager_id != Some(user.id) && !user.admin {
+        return Err(ActionError::Forbidden(
+            "Only the requester, the claiming packager or an admin can edit this request",
+        ));
+    }
+    // Closed requests keep what they were closed with
+    if request.status != "OPEN" {
+        return Err(ActionError::Conflict(closed_message(&request.status)));
+    }
+    let input = patch.apply(&request);
+    input
+        .validate()
+        .map_err(|msg| ActionError::Invalid(msg.to_string()))?;
+    db::update_request(conn, &request, &input, user.id).await?;
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use crate::csrf::{csrf_token, CsrfForm};
+use crate::models::User;
+use crate::{auth, db};
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{get, http, post, web, Error, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+use yarte::Template;
+
+#[derive(Template)]
+#[template(path = "admin.hbs")]
+struct AdminTemplate {
+    base_url: String,
+    users: Vec<User>,
+    current_user: i64,
+    msg: String,
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserForm {
+    action: String,
+}
+
+#[derive(Deserialize)]
+pub struct BulkRequestForm {
+    ids: String,
+    action: String,
+}
+
+/// Admin-only guard for the web pages: redirects anonymous visitors to the login page
+/// and turns everyone else but admins away.
+pub async fn require_admin(conn: &PgPool, id: &Identity) -> Result<User, HttpResponse> {
+    match auth::current_user(conn, id).await {
+        Some(user) if user.admin => Ok(user),
+        Some(_) => Err(HttpResponse::Forbidden()
+            .header(http::header::CONTENT_TYPE, "text/html")
+            .body("Only admins can access this page")),
+        None => Err(HttpResponse::Found()
+            .header(http::header::LOCATION, "/login")
+            .finish()),
+    }
+}
+
+async fn render_admin(
+    conn: &PgPool,
+    session: &Session,
+    base_url: String,
+    user: &User,
+    msg: String,
+) -> Result<HttpResponse, Error> {
+    let users = db::get_users(conn)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let template = AdminTemplate {
+        base_url,
+        users,
+        current_user: user.id,
+        msg,
+        csrf_token: csrf_token(session)?,
+    };
+
+    Ok(HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            template
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        ))
+}
+
+#[get("/admin")]
+pub async fn admin_panel(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let user = match require_admin(&conn, &id).await {
+        Ok(user) => user,
+        Err(response) => return Ok(response),
+    };
+
+    render_admin(&conn, &session, base_url, &user, "".to_owned()).await
+}
+
+#[post("/admin/users/{id}")]
+pub async fn form_user(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    form: CsrfForm<UserForm>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let user = match require_admin(&conn, &id).await {
+        Ok(user) => user,
+        Err(response) => return Ok(response),
+    };
+    let target = (path.0).0;
+    if target == user.id {
+        let msg = "You cannot change your own account here".to_owned();
+        return render_admin(&conn, &session, base_url, &user, msg).await;
+    }
+    let result = match form.action.as_str() {
+        "grant" => db::update_user_admin(&conn, target, true).await,
+        "revoke" => db::update_user_admin(&conn, target, false).await,
+        "lock" => db::update_user_locked(&conn, target, true).await,
+        "unlock" => db::update_user_locked(&conn, target, false).await,
+        "approve" => db::approve_user(&conn, target).await,
+        "reject" => db::delete_pending_user(&conn, target).await,
+        _ => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    result.map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, format!("{}/admin", base_url))
+        .finish())
+}
+
+#[post("/admin/requests")]
+pub async fn form_requests(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    form: CsrfForm<BulkRequestForm>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let user = match require_admin(&conn, &id).await {
+        Ok(user) => user,
+        Err(response) => return Ok(response),
+    };
+    let ids = form
+        .ids
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+        .map(str::parse::<i64>)
+        .collect::<Result<Vec<_>, _>>();
+    let ids = match ids {
+        Ok(ids) => ids,
+        Err(_) => {
+            let msg = "Request IDs must be numbers".to_owned();
+            return render_admin(&conn, &session, base_url, &user, msg).await;
+        }
+    };
+    let mut changed = 0;
+    for request_id in ids.iter() {
+        let result = match form.action.as_str() {
+            "close" => db::close_request_by_id(&conn, *request_id, false, user.id).await,
+            "reject" => db::close_request_by_id(&conn, *request_id, true, user.id).await,
+            "reopen" => db::reopen_request_by_id(&conn, *request_id, user.id).await,
+            _ => return Ok(HttpResponse::BadRequest().finish()),
+        };
+        if result.map_err(|_| HttpResponse::InternalServerError().finish())? {
+            changed += 1;
+        }
+    }
+    let msg = format!("{} of {} requests changed", changed, ids.len());
+
+    render_admin(&conn, &session, base_url, &user, msg).await
+}
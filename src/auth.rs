@@ -1,9 +1,19 @@
-use crate::{db, models::Oauth};
+use crate::actions::ActionError;
+use crate::csrf::{csrf_token, CsrfForm, NoFields};
+use crate::{
+    db,
+    identity::current_session,
+    models::{ApiToken, Oauth, RegisterInput, User, UserSession},
+    oauth::telegram_bot_name,
+    providers::{OauthProviders, ProviderLink, AOSC_PROVIDER},
+    tokens::{generate_token, token_hash, SCOPE_ADMIN, SCOPE_READ, SCOPE_WRITE_REQUESTS},
+};
 use actix_identity::Identity;
+use actix_session::Session;
 use actix_web::{get, post, web, Error};
-use actix_web::{http, HttpResponse};
+use actix_web::{http, HttpRequest, HttpResponse};
 use argonautica;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use yarte::Template;
 
@@ -12,6 +22,9 @@ use yarte::Template;
 struct LoginTemplate {
     msg: String,
     base_url: String,
+    telegram_bot: Option<String>,
+    providers: Vec<ProviderLink>,
+    csrf_token: String,
 }
 
 #[derive(Template)]
@@ -21,6 +34,63 @@ struct PanelTemplate {
     banner_subtitle: String,
     msg: String,
     oauth: Vec<Oauth>,
+    admin: bool,
+    telegram_bot: Option<String>,
+    providers: Vec<ProviderLink>,
+    sessions: Vec<SessionView>,
+    tokens: Vec<ApiToken>,
+    /// Shown once, right after the token has been created
+    new_token: Option<String>,
+    csrf_token: String,
+}
+
+struct SessionView {
+    session: UserSession,
+    current: bool,
+}
+
+#[derive(Template)]
+#[template(path = "register.hbs")]
+struct RegisterTemplate {
+    base_url: String,
+    msg: String,
+    username: String,
+    oauth: Option<PendingOauth>,
+    csrf_token: String,
+}
+
+/// Session key of an external identity that has been verified but is not linked to any user yet
+pub const PENDING_OAUTH_KEY: &str = "pending_oauth";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingOauth {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub oid: String,
+    /// Suggested by the provider for new accounts
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+impl PendingOauth {
+    /// Only AOSC identities are vetted well enough to skip the approval by an admin
+    pub fn is_trusted(&self) -> bool {
+        self.type_ == AOSC_PROVIDER
+    }
+}
+
+pub const IDENTITY_LINKED: &str = "This identity is already linked to another account";
+
+/// Whether the external identity belongs to a user other than `uid`
+pub async fn linked_to_other_user(
+    conn: &PgPool,
+    type_: &str,
+    oid: &str,
+    uid: Option<i64>,
+) -> anyhow::Result<bool> {
+    let owner = db::get_user_by_oid(conn, type_, oid).await?;
+
+    Ok(owner.map(|owner| Some(owner.id) != uid).unwrap_or(false))
 }
 
 #[derive(Deserialize)]
@@ -39,8 +109,21 @@ pub struct AccountForm {
     repeat_password: String,
 }
 
+#[derive(Deserialize)]
+pub struct TokenForm {
+    name: String,
+    /// Checkboxes, only sent when checked
+    write_requests: Option<String>,
+    admin: Option<String>,
+}
+
 #[get("/login")]
-pub async fn login(id: Identity, base_url: String) -> Result<HttpResponse, Error> {
+pub async fn login(
+    id: Identity,
+    session: Session,
+    providers: web::Data<OauthProviders>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
     if let Some(_id) = id.identity() {
         return Ok(HttpResponse::Found()
             .header(http::header::LOCATION, "/account")
@@ -49,6 +132,9 @@ pub async fn login(id: Identity, base_url: String) -> Result<HttpResponse, Error
     let template = LoginTemplate {
         base_url,
         msg: "".to_owned(),
+        telegram_bot: telegram_bot_name(),
+        providers: providers.links(),
+        csrf_token: csrf_token(&session)?,
     };
     return Ok(HttpResponse::Ok()
         .header(http::header::CONTENT_TYPE, "text/html")
@@ -59,6 +145,41 @@ pub async fn login(id: Identity, base_url: String) -> Result<HttpResponse, Error
         ));
 }
 
+/// Renders the login page with a message, for web handlers refusing to log a user in
+pub fn login_page(
+    session: &Session,
+    base_url: String,
+    providers: &OauthProviders,
+    msg: &str,
+) -> Result<HttpResponse, Error> {
+    let template = LoginTemplate {
+        base_url,
+        msg: msg.to_owned(),
+        telegram_bot: telegram_bot_name(),
+        providers: providers.links(),
+        csrf_token: csrf_token(session)?,
+    };
+
+    Ok(HttpResponse::Unauthorized()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            template
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        ))
+}
+
+/// Looks up the logged in user, treating locked accounts as logged out
+pub async fn current_user(conn: &PgPool, id: &Identity) -> Option<User> {
+    let username = id.identity()?;
+    let user = db::get_user_by_username(conn, &username).await.ok()?;
+    if user.locked || !user.approved {
+        return None;
+    }
+
+    Some(user)
+}
+
 pub async fn check_password(
     pool: web::Data<PgPool>,
     username: String,
@@ -68,6 +189,9 @@ pub async fn check_password(
     let user = db::get_user_by_username(&conn, &username)
         .await
         .map_err(|_| HttpResponse::BadRequest().body("Internal Server Error"))?;
+    if user.locked || !user.approved {
+        return Ok(false);
+    }
     let mut verifier = argonautica::Verifier::default();
     let encoded_password = format!("{}:{}", user.id, pwd);
     if let Some(password_hash) = user.password_hash {
@@ -89,35 +213,193 @@ pub async fn hash_password(
     username: String,
     password: &str,
 ) -> Result<String, Error> {
-    let mut hasher = argonautica::Hasher::default();
     let conn = pool.get_ref();
+    let user = db::get_user_by_username(&conn, &username)
+        .await
+        .map_err(|_| HttpResponse::BadRequest().body("Internal Server Error"))?;
+
+    hash_password_with_id(user.id, password).await
+}
+
+pub async fn hash_password_with_id(uid: i64, password: &str) -> Result<String, Error> {
+    let mut hasher = argonautica::Hasher::default();
     hasher
         .configure_iterations(8)
         .configure_memory_size(65536)
         // Not supported by pakreqBot
         .opt_out_of_secret_key(true);
-    let user = db::get_user_by_username(&conn, &username)
-        .await
-        .map_err(|_| HttpResponse::BadRequest().body("Internal Server Error"))?;
-    let encoded = format!("{}:{}", user.id, password);
+    let encoded = format!("{}:{}", uid, password);
     let result = web::block(move || hasher.with_password(encoded).hash()).await?;
 
     Ok(result)
 }
 
+/// Creates a new user. The account needs to be approved by an admin,
+/// unless the user comes with an AOSC identity.
+pub async fn register_user(
+    conn: &PgPool,
+    input: &RegisterInput,
+    oauth: Option<PendingOauth>,
+) -> Result<User, ActionError> {
+    input
+        .validate()
+        .map_err(|msg| ActionError::Invalid(msg.to_string()))?;
+    if db::get_user_by_username(conn, &input.username)
+        .await
+        .is_ok()
+    {
+        return Err(ActionError::Conflict("Username is already taken"));
+    }
+    if let Some(oauth) = &oauth {
+        if linked_to_other_user(conn, &oauth.type_, &oauth.oid, None).await? {
+            return Err(ActionError::Conflict(IDENTITY_LINKED));
+        }
+    }
+    let uid = db::reserve_user_id(conn).await?;
+    let password_hash = hash_password_with_id(uid, &input.password)
+        .await
+        .map_err(|_| ActionError::Internal(anyhow::anyhow!("Failed to hash the password")))?;
+    db::add_user(
+        conn,
+        User {
+            id: uid,
+            username: input.username.clone(),
+            admin: false,
+            password_hash: Some(password_hash),
+            locked: false,
+            approved: oauth
+                .as_ref()
+                .map(PendingOauth::is_trusted)
+                .unwrap_or(false),
+        },
+    )
+    .await?;
+    if let Some(oauth) = oauth {
+        db::add_oauth_info(
+            conn,
+            Oauth {
+                uid,
+                type_: oauth.type_,
+                oid: Some(oauth.oid),
+                token: None,
+            },
+        )
+        .await?;
+    }
+
+    Ok(db::get_user_by_username(conn, &input.username).await?)
+}
+
+#[get("/register")]
+pub async fn register(
+    id: Identity,
+    session: Session,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    if id.identity().is_some() {
+        return Ok(HttpResponse::Found()
+            .header(http::header::LOCATION, "/account")
+            .finish());
+    }
+    let oauth = session.get::<PendingOauth>(PENDING_OAUTH_KEY)?;
+    let template = RegisterTemplate {
+        base_url,
+        msg: "".to_owned(),
+        username: oauth
+            .as_ref()
+            .and_then(|oauth| oauth.username.as_ref())
+            .map(|username| username.to_ascii_lowercase())
+            .unwrap_or_default(),
+        oauth,
+        csrf_token: csrf_token(&session)?,
+    };
+
+    Ok(HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            template
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        ))
+}
+
+#[post("/register")]
+pub async fn form_register(
+    id: Identity,
+    session: Session,
+    form: CsrfForm<RegisterInput>,
+    pool: web::Data<PgPool>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    let oauth = session.get::<PendingOauth>(PENDING_OAUTH_KEY)?;
+    let mut template = RegisterTemplate {
+        base_url,
+        msg: "".to_owned(),
+        username: form.username.clone(),
+        oauth: oauth.clone(),
+        csrf_token: csrf_token(&session)?,
+    };
+    let mut response = match register_user(pool.get_ref(), &form, oauth).await {
+        Ok(user) if user.approved => {
+            session.remove(PENDING_OAUTH_KEY);
+            id.remember(user.username);
+            return Ok(HttpResponse::Found()
+                .header(http::header::LOCATION, "/account")
+                .finish());
+        }
+        Ok(_) => {
+            // The identity has been linked to the new account
+            session.remove(PENDING_OAUTH_KEY);
+            template.oauth = None;
+            template.msg =
+                "Registration successful. Your account is waiting for approval by an admin."
+                    .to_owned();
+            HttpResponse::Ok()
+        }
+        Err(ActionError::Internal(_)) => {
+            template.msg = "Internal Server Error".to_owned();
+            HttpResponse::InternalServerError()
+        }
+        Err(ActionError::Conflict(IDENTITY_LINKED)) => {
+            // Let the user register without the identity
+            session.remove(PENDING_OAUTH_KEY);
+            template.oauth = None;
+            template.msg = IDENTITY_LINKED.to_owned();
+            HttpResponse::Conflict()
+        }
+        Err(err) => {
+            template.msg = err.to_string();
+            HttpResponse::BadRequest()
+        }
+    };
+
+    Ok(response
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            template
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        ))
+}
+
 #[post("/login")]
 pub async fn form_login(
     id: Identity,
-    form: web::Form<LoginForm>,
+    session: Session,
+    form: CsrfForm<LoginForm>,
     pool: web::Data<PgPool>,
+    providers: web::Data<OauthProviders>,
     base_url: String,
 ) -> Result<HttpResponse, Error> {
     let username = form.user.clone();
     let template = LoginTemplate {
         base_url,
         msg: "Invalid credentials".to_owned(),
+        telegram_bot: telegram_bot_name(),
+        providers: providers.links(),
+        csrf_token: csrf_token(&session)?,
     };
-    let is_valid = check_password(pool, username.clone(), &form.pwd)
+    let is_valid = check_password(pool.clone(), username.clone(), &form.pwd)
         .await
         .map_err(|_| {
             HttpResponse::Unauthorized()
@@ -129,6 +411,41 @@ pub async fn form_login(
                 )
         })?;
     if is_valid {
+        // Link the external identity the user signed in with before logging in here
+        if let Some(oauth) = session.get::<PendingOauth>(PENDING_OAUTH_KEY)? {
+            let conn = pool.get_ref();
+            let user = db::get_user_by_username(conn, &username)
+                .await
+                .map_err(|_| HttpResponse::InternalServerError().finish())?;
+            session.remove(PENDING_OAUTH_KEY);
+            if linked_to_other_user(conn, &oauth.type_, &oauth.oid, Some(user.id))
+                .await
+                .map_err(|_| HttpResponse::InternalServerError().finish())?
+            {
+                let template = LoginTemplate {
+                    msg: IDENTITY_LINKED.to_owned(),
+                    ..template
+                };
+                return Ok(HttpResponse::Conflict()
+                    .header(http::header::CONTENT_TYPE, "text/html")
+                    .body(
+                        template
+                            .call()
+                            .unwrap_or("Internal Server Error".to_string()),
+                    ));
+            }
+            db::add_oauth_info(
+                conn,
+                Oauth {
+                    uid: user.id,
+                    type_: oauth.type_,
+                    oid: Some(oauth.oid),
+                    token: None,
+                },
+            )
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        }
         id.remember(username);
         return Ok(HttpResponse::Found()
             .header(http::header::LOCATION, "/account")
@@ -144,16 +461,70 @@ pub async fn form_login(
         ))
 }
 
+/// Builds the account page of `username`, marking the session the page is requested with
+async fn panel_template(
+    conn: &PgPool,
+    session: &Session,
+    base_url: String,
+    username: &str,
+    providers: &OauthProviders,
+    current_session: Option<&str>,
+    msg: String,
+) -> Result<PanelTemplate, Error> {
+    let oauth = db::get_oauth_by_username(conn, username)
+        .await
+        .unwrap_or(vec![]);
+    let user = db::get_user_by_username(conn, username).await.ok();
+    let (sessions, tokens) = match user.as_ref() {
+        Some(user) => (
+            db::get_sessions(conn, user.id).await.unwrap_or(vec![]),
+            db::get_api_tokens(conn, user.id).await.unwrap_or(vec![]),
+        ),
+        None => (vec![], vec![]),
+    };
+
+    Ok(PanelTemplate {
+        base_url,
+        banner_subtitle: format!("Settings for {}", username),
+        msg,
+        oauth,
+        admin: user.map(|user| user.admin).unwrap_or(false),
+        telegram_bot: telegram_bot_name(),
+        providers: providers.links(),
+        sessions: sessions
+            .into_iter()
+            .map(|session| SessionView {
+                current: Some(session.id.as_str()) == current_session,
+                session,
+            })
+            .collect(),
+        tokens,
+        new_token: None,
+        csrf_token: csrf_token(session)?,
+    })
+}
+
 #[get("/account")]
-pub async fn account_panel(id: Identity, base_url: String, pool: web::Data<PgPool>,) -> Result<HttpResponse, Error> {
-    if let Some(id) = id.identity() {
-        let oauth = db::get_oauth_by_username(pool.get_ref(), &id).await.unwrap_or(vec![]);
-        let template = PanelTemplate {
+pub async fn account_panel(
+    id: Identity,
+    session: Session,
+    req: HttpRequest,
+    base_url: String,
+    pool: web::Data<PgPool>,
+    providers: web::Data<OauthProviders>,
+) -> Result<HttpResponse, Error> {
+    if let Some(user) = current_user(pool.get_ref(), &id).await {
+        let current = current_session(&req);
+        let template = panel_template(
+            pool.get_ref(),
+            &session,
             base_url,
-            banner_subtitle: format!("Settings for {}", id),
-            msg: "".to_owned(),
-            oauth,
-        };
+            &user.username,
+            &providers,
+            current.as_deref(),
+            "".to_owned(),
+        )
+        .await?;
         return Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "text/html")
             .body(
@@ -162,6 +533,8 @@ pub async fn account_panel(id: Identity, base_url: String, pool: web::Data<PgPoo
                     .unwrap_or("Internal Server Error".to_string()),
             ));
     }
+    // Ends the session of locked users, which would otherwise be sent back here by `/login`
+    id.forget();
     return Ok(HttpResponse::Found()
         .header(http::header::LOCATION, "/login")
         .finish());
@@ -170,19 +543,27 @@ pub async fn account_panel(id: Identity, base_url: String, pool: web::Data<PgPoo
 #[post("/account")]
 pub async fn form_account(
     id: Identity,
-    form: web::Form<AccountForm>,
+    session: Session,
+    req: HttpRequest,
+    form: CsrfForm<AccountForm>,
     pool: web::Data<PgPool>,
+    providers: web::Data<OauthProviders>,
     base_url: String,
 ) -> Result<HttpResponse, Error> {
-    if let Some(id) = id.identity() {
-        let oauth = db::get_oauth_by_username(pool.get_ref(), &id).await.unwrap_or(vec![]);
+    if let Some(user) = current_user(pool.get_ref(), &id).await {
+        let username = user.username.clone();
+        let current = current_session(&req);
         if form.new_password != form.repeat_password {
-            let template = PanelTemplate {
+            let template = panel_template(
+                pool.get_ref(),
+                &session,
                 base_url,
-                oauth,
-                banner_subtitle: format!("Settings for {}", id),
-                msg: "New password and Confirm new password mismatch!".to_owned(),
-            };
+                &username,
+                &providers,
+                current.as_deref(),
+                "New password and Confirm new password mismatch!".to_owned(),
+            )
+            .await?;
             return Ok(HttpResponse::Ok()
                 .header(http::header::CONTENT_TYPE, "text/html")
                 .body(
@@ -191,37 +572,167 @@ pub async fn form_account(
                         .unwrap_or("Internal Server Error".to_string()),
                 ));
         }
-        let mut template = PanelTemplate {
-            oauth,
-            base_url: base_url.clone(),
-            banner_subtitle: format!("Settings for {}", id),
-            msg: "Current password is incorrect!".to_owned(),
-        };
-        let is_password_correct = check_password(pool.clone(), id.clone(), &form.current_password)
-            .await
-            .map_err(|_| {
-                HttpResponse::Unauthorized()
-                    .header(http::header::CONTENT_TYPE, "text/html")
-                    .body(
-                        template
-                            .call()
-                            .unwrap_or("Internal Server Error".to_string()),
-                    )
-            })?;
+        let template = panel_template(
+            pool.get_ref(),
+            &session,
+            base_url.clone(),
+            &username,
+            &providers,
+            current.as_deref(),
+            "Current password is incorrect!".to_owned(),
+        )
+        .await?;
+        let is_password_correct =
+            check_password(pool.clone(), username.clone(), &form.current_password)
+                .await
+                .map_err(|_| {
+                    HttpResponse::Unauthorized()
+                        .header(http::header::CONTENT_TYPE, "text/html")
+                        .body(
+                            template
+                                .call()
+                                .unwrap_or("Internal Server Error".to_string()),
+                        )
+                })?;
         if is_password_correct {
-            let password_hash = hash_password(pool.clone(), id.clone(), &form.new_password).await?;
+            let password_hash =
+                hash_password(pool.clone(), username.clone(), &form.new_password).await?;
             let conn = pool.get_ref();
-            db::update_password_hash(&conn, id.clone(), password_hash)
+            db::update_password_hash(&conn, username.clone(), password_hash)
                 .await
                 .map_err(|_| HttpResponse::BadRequest().body("Internal Server Error"))?;
-            template.msg = "Password changed successfully".to_owned();
-            return Ok(HttpResponse::InternalServerError().body(
+            // Log out everywhere else, in case the old password was compromised
+            db::delete_other_sessions(&conn, user.id, current.as_deref())
+                .await
+                .map_err(|_| HttpResponse::InternalServerError().finish())?;
+            db::delete_user_refresh_tokens(&conn, user.id)
+                .await
+                .map_err(|_| HttpResponse::InternalServerError().finish())?;
+            let template = panel_template(
+                &conn,
+                &session,
+                base_url,
+                &username,
+                &providers,
+                current.as_deref(),
+                "Password changed successfully. Other sessions have been logged out.".to_owned(),
+            )
+            .await?;
+            return Ok(HttpResponse::Ok()
+                .header(http::header::CONTENT_TYPE, "text/html")
+                .body(
+                    template
+                        .call()
+                        .unwrap_or("Internal Server Error".to_string()),
+                ));
+        }
+        return Ok(HttpResponse::Unauthorized()
+            .header(http::header::CONTENT_TYPE, "text/html")
+            .body(
                 template
                     .call()
                     .unwrap_or("Internal Server Error".to_string()),
             ));
+    }
+    return Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish());
+}
+
+#[post("/account/sessions/{id}/revoke")]
+pub async fn revoke_session(
+    id: Identity,
+    _form: CsrfForm<NoFields>,
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = current_user(&conn, &id).await {
+        let session_id = (path.0).0.clone();
+        let revoked = db::delete_user_session(&conn, user.id, &session_id)
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        if !revoked {
+            return Ok(HttpResponse::NotFound().finish());
         }
-        return Ok(HttpResponse::Unauthorized()
+        if current_session(&req).as_deref() == Some(session_id.as_str()) {
+            id.forget();
+            return Ok(HttpResponse::Found()
+                .header(http::header::LOCATION, "/")
+                .finish());
+        }
+        return Ok(HttpResponse::Found()
+            .header(http::header::LOCATION, "/account")
+            .finish());
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/account/tokens")]
+pub async fn create_token(
+    id: Identity,
+    session: Session,
+    req: HttpRequest,
+    form: CsrfForm<TokenForm>,
+    pool: web::Data<PgPool>,
+    providers: web::Data<OauthProviders>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = current_user(&conn, &id).await {
+        let current = current_session(&req);
+        let name = form.name.trim();
+        if name.is_empty() || (form.admin.is_some() && !user.admin) {
+            let msg = if name.is_empty() {
+                "Token name must not be empty!"
+            } else {
+                "Only admins can create tokens with the admin scope!"
+            };
+            let template = panel_template(
+                &conn,
+                &session,
+                base_url,
+                &user.username,
+                &providers,
+                current.as_deref(),
+                msg.to_owned(),
+            )
+            .await?;
+            return Ok(HttpResponse::BadRequest()
+                .header(http::header::CONTENT_TYPE, "text/html")
+                .body(
+                    template
+                        .call()
+                        .unwrap_or("Internal Server Error".to_string()),
+                ));
+        }
+        let mut scopes = vec![SCOPE_READ.to_string()];
+        if form.write_requests.is_some() {
+            scopes.push(SCOPE_WRITE_REQUESTS.to_string());
+        }
+        if form.admin.is_some() {
+            scopes.push(SCOPE_ADMIN.to_string());
+        }
+        let token = generate_token();
+        db::add_api_token(&conn, user.id, name, &token_hash(&token), &scopes)
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        let mut template = panel_template(
+            &conn,
+            &session,
+            base_url,
+            &user.username,
+            &providers,
+            current.as_deref(),
+            "Token created. Copy it now, it will not be shown again.".to_owned(),
+        )
+        .await?;
+        template.new_token = Some(token);
+        return Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "text/html")
             .body(
                 template
@@ -229,9 +740,35 @@ pub async fn form_account(
                     .unwrap_or("Internal Server Error".to_string()),
             ));
     }
-    return Ok(HttpResponse::Found()
+
+    Ok(HttpResponse::Found()
         .header(http::header::LOCATION, "/login")
-        .finish());
+        .finish())
+}
+
+#[post("/account/tokens/{id}/revoke")]
+pub async fn revoke_token(
+    id: Identity,
+    _form: CsrfForm<NoFields>,
+    pool: web::Data<PgPool>,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = current_user(&conn, &id).await {
+        let revoked = db::delete_api_token(&conn, user.id, (path.0).0)
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        if !revoked {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        return Ok(HttpResponse::Found()
+            .header(http::header::LOCATION, "/account")
+            .finish());
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
 }
 
 #[get("/logout")]
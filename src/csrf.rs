@@ -0,0 +1,102 @@
+//! CSRF tokens for the forms of the web pages, kept in the private session cookie
+use crate::tokens::random_token;
+use actix_session::Session;
+use actix_web::{dev::Payload, http, web, Error, FromRequest, HttpRequest, HttpResponse};
+use ring::constant_time::verify_slices_are_equal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+
+/// Session key of the token expected in the `csrf_token` field of every form
+const CSRF_TOKEN_KEY: &str = "csrf_token";
+
+#[derive(Deserialize)]
+struct CsrfField {
+    csrf_token: String,
+}
+
+/// For forms without any other field
+#[derive(Deserialize)]
+pub struct NoFields {}
+
+/// Returns the token of the session to embed in the forms, creating it if needed
+pub fn csrf_token(session: &Session) -> Result<String, Error> {
+    if let Some(token) = session.get::<String>(CSRF_TOKEN_KEY)? {
+        return Ok(token);
+    }
+    let token = random_token();
+    session.set(CSRF_TOKEN_KEY, &token)?;
+
+    Ok(token)
+}
+
+#[inline]
+fn verify(expected: Option<&str>, token: &str) -> bool {
+    match expected {
+        Some(expected) => verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok(),
+        None => false,
+    }
+}
+
+/// Like `web::Form`, but rejects the request unless it carries the CSRF token of the session
+pub struct CsrfForm<T>(pub T);
+
+impl<T> Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for CsrfForm<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let session = Session::extract(req);
+        let body = web::Bytes::from_request(req, payload);
+        Box::pin(async move {
+            let session = session.await?;
+            let body = body.await?;
+            let body =
+                std::str::from_utf8(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
+            let field = web::Query::<CsrfField>::from_query(body).map_err(|_| forbidden())?;
+            let expected = session.get::<String>(CSRF_TOKEN_KEY)?;
+            if !verify(expected.as_deref(), &field.csrf_token) {
+                return Err(forbidden().into());
+            }
+            let form = web::Query::<T>::from_query(body)
+                .map_err(|_| HttpResponse::BadRequest().finish())?;
+
+            Ok(CsrfForm(form.into_inner()))
+        })
+    }
+}
+
+#[inline]
+fn forbidden() -> HttpResponse {
+    HttpResponse::Forbidden()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body("Invalid CSRF token, please reload the page and try again")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_token_is_accepted() {
+        assert!(verify(Some("token"), "token"));
+    }
+
+    #[test]
+    fn wrong_or_missing_token_is_rejected() {
+        assert!(!verify(Some("token"), "other"));
+        assert!(!verify(Some("token"), ""));
+        assert!(!verify(None, "token"));
+    }
+}
@@ -1,6 +1,58 @@
-use crate::models::{Oauth, Request, RequestInput, RequestStr, User};
-use anyhow::{anyhow, Result};
-use sqlx::PgPool;
+use crate::models::{
+    ApiToken, Comment, IterEntry, IterEntryInput, IterEntryNode, IterPlan, IterPlanInput, Oauth,
+    Request, RequestEvent, RequestFilter, RequestInput, RequestStr, SearchResult, User,
+    UserSession,
+};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Raised by the `check_entry_date` trigger when an entry is dated outside of its plan
+#[derive(Debug)]
+pub struct EntryDateError {
+    pub begin_date: NaiveDateTime,
+    pub end_date: NaiveDateTime,
+}
+
+impl fmt::Display for EntryDateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Entry date must be between {} and {}",
+            self.begin_date, self.end_date
+        )
+    }
+}
+
+impl std::error::Error for EntryDateError {}
+
+#[inline]
+fn is_entry_date_violation(err: &sqlx::Error) -> bool {
+    match err.as_database_error() {
+        // P0001: raise_exception, emitted by `RAISE EXCEPTION` in PL/pgSQL
+        Some(err) => {
+            err.code().as_deref() == Some("P0001")
+                && err.message().starts_with("Entry date is not between")
+        }
+        None => false,
+    }
+}
+
+async fn entry_date_error(conn: &PgPool, plan_id_: i64, err: sqlx::Error) -> anyhow::Error {
+    if !is_entry_date_violation(&err) {
+        return err.into();
+    }
+    match get_plan_by_id(conn, plan_id_).await {
+        Ok(Some(plan)) => EntryDateError {
+            begin_date: plan.begin_date,
+            end_date: plan.end_date,
+        }
+        .into(),
+        _ => err.into(),
+    }
+}
 
 pub async fn get_open_requests(conn: &PgPool) -> Result<Vec<Request>> {
     let records = sqlx::query!(r#"SELECT * FROM request WHERE status = 'OPEN' ORDER BY id DESC"#)
@@ -25,14 +77,159 @@ pub async fn get_open_requests(conn: &PgPool) -> Result<Vec<Request>> {
     Ok(open_requests)
 }
 
-pub async fn get_open_requests_json(conn: &PgPool) -> Result<String> {
+const REQUEST_FILTER_CLAUSE: &str = r#"
+    FROM request r
+    LEFT JOIN "user" requester ON requester.id = r.requester_id
+    LEFT JOIN "user" packager ON packager.id = r.packager_id
+    WHERE ($1::TEXT IS NULL OR r.status = $1)
+    AND ($2::TEXT IS NULL OR r.type = $2)
+    AND ($3::TEXT IS NULL OR requester.username = $3)
+    AND ($4::TEXT IS NULL OR packager.username = $4)
+    AND ($5::DATE IS NULL OR r.pub_date >= $5)
+    AND ($6::DATE IS NULL OR r.pub_date <= $6)
+    AND ($7::TEXT IS NULL OR strpos(lower(r.name), lower($7)) > 0)
+"#;
+
+/// Returns the total number of matching requests, a page of them, and the cursor of the next page
+/// if there is one. `filter` must have been validated, since the sort key and order are spliced
+/// into the query.
+pub async fn get_requests(
+    conn: &PgPool,
+    filter: &RequestFilter,
+) -> Result<(i64, Vec<Request>, Option<i64>)> {
+    let sort_key = match filter.sort.as_deref() {
+        Some("date") => "pub_date",
+        Some("name") => "name",
+        _ => "id",
+    };
+    let (order, comparison) = match filter.order.as_deref() {
+        Some("asc") => ("ASC", ">"),
+        _ => ("DESC", "<"),
+    };
+    let sql = format!(
+        r#"SELECT r.id, r.status, r.type AS type_, r.name, r.description, r.requester_id,
+        r.packager_id, r.pub_date, r.note
+        {filter}
+        AND ($8::BIGINT IS NULL OR (r.{key}, r.id) {cmp} (SELECT {key}, id FROM request WHERE id = $8))
+        ORDER BY r.{key} {order}, r.id {order}
+        LIMIT $9"#,
+        filter = REQUEST_FILTER_CLAUSE,
+        key = sort_key,
+        cmp = comparison,
+        order = order
+    );
+    let mut requests = sqlx::query_as::<_, Request>(&sql)
+        .bind(filter.status())
+        .bind(filter.type_.as_deref())
+        .bind(filter.requester.as_deref())
+        .bind(filter.packager.as_deref())
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.name.as_deref())
+        .bind(filter.cursor)
+        // One more row tells whether there is a next page
+        .bind(filter.limit() + 1)
+        .fetch_all(conn)
+        .await?;
+    let next_cursor = if requests.len() as i64 > filter.limit() {
+        requests.truncate(filter.limit() as usize);
+        requests.last().map(|request| request.id)
+    } else {
+        None
+    };
+    let sql = format!("SELECT COUNT(*) {}", REQUEST_FILTER_CLAUSE);
+    let (total,) = sqlx::query_as::<_, (i64,)>(&sql)
+        .bind(filter.status())
+        .bind(filter.type_.as_deref())
+        .bind(filter.requester.as_deref())
+        .bind(filter.packager.as_deref())
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.name.as_deref())
+        .fetch_one(conn)
+        .await?;
+
+    Ok((total, requests, next_cursor))
+}
+
+/// Escapes `headline` for HTML and turns the match delimiters emitted by `ts_headline` into `<mark>` tags
+fn highlight(headline: &str) -> String {
+    let mut result = String::with_capacity(headline.len());
+    for c in headline.chars() {
+        match c {
+            '\u{2}' => result.push_str("<mark>"),
+            '\u{3}' => result.push_str("</mark>"),
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#x27;"),
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Searches requests of any status, best matches first.
+/// The weighted vector must match `request_search_idx` for the index to be used.
+pub async fn search_requests(conn: &PgPool, query: &str, limit: i64) -> Result<Vec<SearchResult>> {
     let records = sqlx::query!(
-        r#"SELECT json_agg(request)::TEXT AS json FROM request WHERE status = 'OPEN'"#
+        r#"
+        SELECT r.id, r.status, r.type, r.name, r.pub_date,
+        ts_rank(
+            setweight(to_tsvector('english', r.name), 'A') ||
+            setweight(to_tsvector('english', coalesce(r.description, '')), 'B') ||
+            setweight(to_tsvector('english', coalesce(r.note, '')), 'C'),
+            q
+        ) AS "rank!",
+        ts_headline('english', r.name, q,
+            'HighlightAll=true, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS "name_headline!",
+        ts_headline('english', coalesce(r.description, ''), q,
+            'StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS "description_headline!"
+        FROM request r, websearch_to_tsquery('english', $1) q
+        WHERE (
+            setweight(to_tsvector('english', r.name), 'A') ||
+            setweight(to_tsvector('english', coalesce(r.description, '')), 'B') ||
+            setweight(to_tsvector('english', coalesce(r.note, '')), 'C')
+        ) @@ q
+        ORDER BY 6 DESC, r.id DESC
+        LIMIT $2
+        "#,
+        query,
+        limit
     )
-    .fetch_one(conn)
+    .fetch_all(conn)
     .await?;
+    let mut results = Vec::new();
+    results.reserve(records.len());
+    for record in records {
+        results.push(SearchResult {
+            id: record.id,
+            status: record.status,
+            type_: record.r#type,
+            name: record.name,
+            pub_date: record.pub_date,
+            rank: record.rank,
+            name_headline: highlight(&record.name_headline),
+            description_headline: highlight(&record.description_headline),
+        });
+    }
 
-    Ok(records.json.ok_or(anyhow!("PG returned empty string"))?)
+    Ok(results)
+}
+
+pub async fn get_request_by_id(conn: &PgPool, id_: i64) -> Result<Option<Request>> {
+    let request = sqlx::query_as!(
+        Request,
+        r#"SELECT id, status, type AS type_, name, description, requester_id, packager_id, pub_date, note
+        FROM request WHERE id = $1"#,
+        id_
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(request)
 }
 
 pub async fn get_request_detail_by_id(conn: &PgPool, id_: i64) -> Result<RequestStr> {
@@ -62,9 +259,57 @@ pub async fn get_request_detail_by_id(conn: &PgPool, id_: i64) -> Result<Request
     Ok(result)
 }
 
+pub async fn get_request_history(conn: &PgPool, id_: i64) -> Result<Vec<RequestEvent>> {
+    let events = sqlx::query_as!(
+        RequestEvent,
+        r#"SELECT e.id, e.request_id, u.username AS "user?", e.field, e.old_value, e.new_value, e.created_at
+        FROM request_event e LEFT JOIN "user" u ON u.id = e.user_id
+        WHERE e.request_id = $1 ORDER BY e.created_at, e.id"#,
+        id_
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(events)
+}
+
+/// Lists the comments of a request, leaving out the deleted ones
+pub async fn get_comments(conn: &PgPool, request_id_: i64) -> Result<Vec<Comment>> {
+    let comments = sqlx::query_as!(
+        Comment,
+        r#"SELECT c.id, c.request_id, c.author_id, u.username AS author, c.body, c.created_at, c.edited_at
+        FROM request_comment c INNER JOIN "user" u ON u.id = c.author_id
+        WHERE c.request_id = $1 AND NOT c.deleted ORDER BY c.created_at, c.id"#,
+        request_id_
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(comments)
+}
+
+pub async fn get_comment_by_id(
+    conn: &PgPool,
+    request_id_: i64,
+    id_: i64,
+) -> Result<Option<Comment>> {
+    let comment = sqlx::query_as!(
+        Comment,
+        r#"SELECT c.id, c.request_id, c.author_id, u.username AS author, c.body, c.created_at, c.edited_at
+        FROM request_comment c INNER JOIN "user" u ON u.id = c.author_id
+        WHERE c.id = $1 AND c.request_id = $2 AND NOT c.deleted"#,
+        id_,
+        request_id_
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(comment)
+}
+
 pub async fn get_user_by_username(conn: &PgPool, username_: &str) -> Result<User> {
     let record = sqlx::query!(
-        r#"SELECT id, username, password_hash FROM "user" WHERE username = $1"#,
+        r#"SELECT id, username, admin, password_hash, locked, approved FROM "user" WHERE username = $1"#,
         username_
     )
     .fetch_one(conn)
@@ -73,21 +318,34 @@ pub async fn get_user_by_username(conn: &PgPool, username_: &str) -> Result<User
     Ok(User {
         id: record.id,
         username: record.username,
-        admin: false,
+        admin: record.admin,
         password_hash: record.password_hash,
+        locked: record.locked,
+        approved: record.approved,
     })
 }
 
-pub async fn get_user_by_oid(conn: &PgPool, service: &str, oid: &str) -> Result<User> {
+pub async fn get_users(conn: &PgPool) -> Result<Vec<User>> {
+    let users = sqlx::query_as!(
+        User,
+        r#"SELECT id, username, admin, password_hash, locked, approved FROM "user" ORDER BY approved, id"#
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(users)
+}
+
+pub async fn get_user_by_oid(conn: &PgPool, service: &str, oid: &str) -> Result<Option<User>> {
     let user_ = sqlx::query_as!(
         User,
-        r#"SELECT u.id, u.username, u.admin, u.password_hash
+        r#"SELECT u.id, u.username, u.admin, u.password_hash, u.locked, u.approved
         FROM "user" u INNER JOIN oauth o ON o.uid = u.id
         WHERE o.oid = $1 AND o.type = $2"#,
         oid,
         service
     )
-    .fetch_one(conn)
+    .fetch_optional(conn)
     .await?;
 
     Ok(user_)
@@ -115,12 +373,330 @@ pub async fn get_oauth_by_username(conn: &PgPool, username: &str) -> Result<Vec<
     Ok(oauth)
 }
 
+/// Returns the username and last activity of a session, unless it has been idle since `seen_after`
+pub async fn get_session_user(
+    conn: &PgPool,
+    id_: &str,
+    seen_after: DateTime<Utc>,
+) -> Result<Option<(String, DateTime<Utc>)>> {
+    let record = sqlx::query!(
+        r#"SELECT u.username, s.last_seen FROM user_session s INNER JOIN "user" u ON u.id = s.uid
+        WHERE s.id = $1 AND s.last_seen > $2"#,
+        id_,
+        seen_after
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|record| (record.username, record.last_seen)))
+}
+
+pub async fn get_sessions(conn: &PgPool, uid_: i64) -> Result<Vec<UserSession>> {
+    let sessions = sqlx::query_as!(
+        UserSession,
+        r#"SELECT id, user_agent, ip, created_at, last_seen FROM user_session
+        WHERE uid = $1 ORDER BY last_seen DESC"#,
+        uid_
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(sessions)
+}
+
+pub async fn get_api_tokens(conn: &PgPool, uid_: i64) -> Result<Vec<ApiToken>> {
+    let tokens = sqlx::query_as!(
+        ApiToken,
+        r#"SELECT id, name, scopes, created_at, last_used FROM api_token
+        WHERE uid = $1 ORDER BY created_at DESC"#,
+        uid_
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(tokens)
+}
+
+/// Returns the username and scopes of an API token, and records that it has been used
+pub async fn use_api_token(
+    conn: &PgPool,
+    token_hash_: &str,
+) -> Result<Option<(String, Vec<String>)>> {
+    let record = sqlx::query!(
+        r#"UPDATE api_token t SET last_used = CURRENT_TIMESTAMP FROM "user" u
+        WHERE u.id = t.uid AND t.token_hash = $1 RETURNING u.username, t.scopes"#,
+        token_hash_
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|record| (record.username, record.scopes)))
+}
+
+pub async fn is_jwt_revoked(conn: &PgPool, jti_: &str) -> Result<bool> {
+    let record = sqlx::query!(r#"SELECT jti FROM revoked_jwt WHERE jti = $1"#, jti_)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(record.is_some())
+}
+
+pub async fn get_plans(conn: &PgPool) -> Result<Vec<IterPlan>> {
+    let plans = sqlx::query_as!(
+        IterPlan,
+        r#"SELECT id, title, begin_date, end_date, notes FROM iter_plans ORDER BY begin_date DESC"#
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(plans)
+}
+
+pub async fn get_plan_by_id(conn: &PgPool, id_: i64) -> Result<Option<IterPlan>> {
+    let plan = sqlx::query_as!(
+        IterPlan,
+        r#"SELECT id, title, begin_date, end_date, notes FROM iter_plans WHERE id = $1"#,
+        id_
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(plan)
+}
+
+pub async fn get_entry_by_id(conn: &PgPool, id_: i64) -> Result<Option<IterEntry>> {
+    let entry = sqlx::query_as!(
+        IterEntry,
+        r#"SELECT id, plan_id, parent_id, name, done, date, category, version, origin, target, url, description
+        FROM iter_entries WHERE id = $1"#,
+        id_
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(entry)
+}
+
+/// Returns whether `ancestor` is `id_` itself or one of its (transitive) parents
+pub async fn is_entry_ancestor(conn: &PgPool, ancestor: i64, id_: i64) -> Result<bool> {
+    let record = sqlx::query!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id FROM iter_entries WHERE id = $1
+            UNION
+            SELECT e.id, e.parent_id FROM iter_entries e INNER JOIN ancestors a ON e.id = a.parent_id
+        )
+        SELECT EXISTS(SELECT 1 FROM ancestors WHERE id = $2) AS "found!"
+        "#,
+        id_,
+        ancestor
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(record.found)
+}
+
+/// Fetches all entries of a plan, nested under their parents
+pub async fn get_plan_entry_tree(conn: &PgPool, plan_id_: i64) -> Result<Vec<IterEntryNode>> {
+    let entries = sqlx::query_as!(
+        IterEntry,
+        r#"SELECT id, plan_id, parent_id, name, done, date, category, version, origin, target, url, description
+        FROM iter_entries WHERE plan_id = $1 ORDER BY category, date, id"#,
+        plan_id_
+    )
+    .fetch_all(conn)
+    .await?;
+    let mut children: HashMap<Option<i64>, Vec<IterEntry>> = HashMap::new();
+    for entry in entries {
+        children.entry(entry.parent_id).or_default().push(entry);
+    }
+
+    Ok(attach_entries(None, &mut children))
+}
+
+fn attach_entries(
+    parent: Option<i64>,
+    children: &mut HashMap<Option<i64>, Vec<IterEntry>>,
+) -> Vec<IterEntryNode> {
+    children
+        .remove(&parent)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| IterEntryNode {
+            children: attach_entries(Some(entry.id), children),
+            entry,
+        })
+        .collect()
+}
+
 // Writables
 
-pub async fn close_request_by_id(conn: &PgPool, id_: i64, reject: bool) -> Result<()> {
+/// Records a change to a request. Call this in the same transaction as the change itself.
+async fn add_request_event(
+    tx: &mut Transaction<'_, Postgres>,
+    request_id: i64,
+    user_id: i64,
+    field: &str,
+    old_value: Option<&str>,
+    new_value: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"INSERT INTO request_event (request_id, user_id, field, old_value, new_value)
+        VALUES ($1, $2, $3, $4, $5)"#,
+        request_id,
+        user_id,
+        field,
+        old_value,
+        new_value
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn add_request(
+    conn: &PgPool,
+    requester_id: i64,
+    input: &RequestInput,
+) -> Result<Request> {
+    let mut tx = conn.begin().await?;
+    let request = sqlx::query_as!(
+        Request,
+        r#"INSERT INTO request (status, type, name, description, requester_id, pub_date, note)
+        VALUES ('OPEN', $1, $2, $3, $4, $5, $6)
+        RETURNING id, status, type AS type_, name, description, requester_id, packager_id, pub_date, note"#,
+        input.type_,
+        input.name,
+        input.description,
+        requester_id,
+        Utc::now().date().naive_utc(),
+        input.note
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    add_request_event(
+        &mut tx,
+        request.id,
+        requester_id,
+        "status",
+        None,
+        Some("OPEN"),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(request)
+}
+
+/// Marks an open request as DONE or REJECTED.
+/// Returns `false` if the request was not open anymore.
+pub async fn close_request_by_id(
+    conn: &PgPool,
+    id_: i64,
+    reject: bool,
+    user_id: i64,
+) -> Result<bool> {
+    let status = if reject { "REJECTED" } else { "DONE" };
+    let mut tx = conn.begin().await?;
+    let result = sqlx::query!(
+        r#"UPDATE request SET status = $1 WHERE id = $2 AND status = 'OPEN'"#,
+        status,
+        id_
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() != 1 {
+        return Ok(false);
+    }
+    add_request_event(&mut tx, id_, user_id, "status", Some("OPEN"), Some(status)).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Changes the packager of a request, provided it is still claimed by `expected`.
+/// Returns `false` if the request was modified in the meantime.
+pub async fn update_request_packager(
+    conn: &PgPool,
+    id_: i64,
+    expected: Option<i64>,
+    packager_id: Option<i64>,
+    user_id: i64,
+) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    let result = sqlx::query!(
+        r#"UPDATE request SET packager_id = $1
+        WHERE id = $2 AND status = 'OPEN' AND packager_id IS NOT DISTINCT FROM $3"#,
+        packager_id,
+        id_,
+        expected
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() != 1 {
+        return Ok(false);
+    }
+    sqlx::query!(
+        r#"INSERT INTO request_event (request_id, user_id, field, old_value, new_value)
+        VALUES ($1, $2, 'packager',
+            (SELECT username FROM "user" WHERE id = $3),
+            (SELECT username FROM "user" WHERE id = $4))"#,
+        id_,
+        user_id,
+        expected,
+        packager_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Reopens a closed request. Returns `false` if the request was already open.
+pub async fn reopen_request_by_id(conn: &PgPool, id_: i64, user_id: i64) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    let record = sqlx::query!(
+        r#"UPDATE request r SET status = 'OPEN'
+        FROM (SELECT id, status FROM request WHERE id = $1 FOR UPDATE) old
+        WHERE r.id = old.id AND old.status <> 'OPEN'
+        RETURNING old.status AS "old_status!""#,
+        id_
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let old_status = match record {
+        Some(record) => record.old_status,
+        None => return Ok(false),
+    };
+    add_request_event(
+        &mut tx,
+        id_,
+        user_id,
+        "status",
+        Some(&old_status),
+        Some("OPEN"),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub async fn update_user_admin(conn: &PgPool, id_: i64, admin: bool) -> Result<()> {
+    sqlx::query!(r#"UPDATE "user" SET admin = $1 WHERE id = $2"#, admin, id_)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_user_locked(conn: &PgPool, id_: i64, locked: bool) -> Result<()> {
     sqlx::query!(
-        r#"UPDATE request SET status = $1 WHERE id = $2"#,
-        if reject { "REJECTED" } else { "DONE" },
+        r#"UPDATE "user" SET locked = $1 WHERE id = $2"#,
+        locked,
         id_
     )
     .execute(conn)
@@ -141,13 +717,27 @@ pub async fn update_password_hash(conn: &PgPool, username_: String, hash: String
     Ok(())
 }
 
+/// Allocates an ID for a new user, since password hashes are salted with it
+pub async fn reserve_user_id(conn: &PgPool) -> Result<i64> {
+    let record =
+        sqlx::query!(r#"SELECT nextval(pg_get_serial_sequence('public."user"', 'id')) AS "id!""#)
+            .fetch_one(conn)
+            .await?;
+
+    Ok(record.id)
+}
+
 pub async fn add_user(conn: &PgPool, user_: User) -> Result<()> {
     let mut tx = conn.begin().await?;
     sqlx::query!(
-        r#"INSERT INTO "user" (username, admin, password_hash) VALUES ($1, $2, $3)"#,
+        r#"INSERT INTO "user" (id, username, admin, password_hash, locked, approved)
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        user_.id,
         user_.username,
         user_.admin,
-        user_.password_hash
+        user_.password_hash,
+        user_.locked,
+        user_.approved
     )
     .execute(&mut tx)
     .await?;
@@ -156,6 +746,31 @@ pub async fn add_user(conn: &PgPool, user_: User) -> Result<()> {
     Ok(())
 }
 
+pub async fn approve_user(conn: &PgPool, id_: i64) -> Result<()> {
+    sqlx::query!(r#"UPDATE "user" SET approved = true WHERE id = $1"#, id_)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes a registration that has not been approved yet
+pub async fn delete_pending_user(conn: &PgPool, id_: i64) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"DELETE FROM oauth WHERE uid = (SELECT id FROM "user" WHERE id = $1 AND NOT approved)"#,
+        id_
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"DELETE FROM "user" WHERE id = $1 AND NOT approved"#, id_)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
 pub async fn add_oauth_info(conn: &PgPool, info: Oauth) -> Result<()> {
     let mut tx = conn.begin().await?;
     sqlx::query!(
@@ -186,3 +801,440 @@ pub async fn delete_oauth_info(conn: &PgPool, info: Oauth) -> Result<()> {
 
     Ok(())
 }
+
+pub async fn add_session(
+    conn: &PgPool,
+    id_: &str,
+    username_: &str,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"INSERT INTO user_session (id, uid, user_agent, ip)
+        SELECT $1, id, $3, $4 FROM "user" WHERE username = $2"#,
+        id_,
+        username_,
+        user_agent,
+        ip
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn touch_session(conn: &PgPool, id_: &str) -> Result<()> {
+    sqlx::query!(
+        r#"UPDATE user_session SET last_seen = CURRENT_TIMESTAMP WHERE id = $1"#,
+        id_
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_session(conn: &PgPool, id_: &str) -> Result<()> {
+    sqlx::query!(r#"DELETE FROM user_session WHERE id = $1"#, id_)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Revokes a session of the given user. Returns `false` if there is no such session.
+pub async fn delete_user_session(conn: &PgPool, uid_: i64, id_: &str) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    let result = sqlx::query!(
+        r#"DELETE FROM user_session WHERE uid = $1 AND id = $2"#,
+        uid_,
+        id_
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes all sessions of the given user but `keep`
+pub async fn delete_other_sessions(conn: &PgPool, uid_: i64, keep: Option<&str>) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"DELETE FROM user_session WHERE uid = $1 AND id IS DISTINCT FROM $2"#,
+        uid_,
+        keep
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn add_api_token(
+    conn: &PgPool,
+    uid_: i64,
+    name_: &str,
+    token_hash_: &str,
+    scopes_: &[String],
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"INSERT INTO api_token (uid, name, token_hash, scopes) VALUES ($1, $2, $3, $4)"#,
+        uid_,
+        name_,
+        token_hash_,
+        scopes_
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Revokes an API token of the given user. Returns `false` if there is no such token.
+pub async fn delete_api_token(conn: &PgPool, uid_: i64, id_: i64) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    let result = sqlx::query!(
+        r#"DELETE FROM api_token WHERE uid = $1 AND id = $2"#,
+        uid_,
+        id_
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn add_refresh_token(
+    conn: &PgPool,
+    id_: &str,
+    username_: &str,
+    jti_: &str,
+    expires_at_: DateTime<Utc>,
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(r#"DELETE FROM refresh_token WHERE expires_at < CURRENT_TIMESTAMP"#)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO refresh_token (id, uid, jti, expires_at)
+        SELECT $1, id, $3, $4 FROM "user" WHERE username = $2"#,
+        id_,
+        username_,
+        jti_,
+        expires_at_
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Deletes a refresh token, so that it can only be used once, and returns the username it belongs to
+pub async fn take_refresh_token(conn: &PgPool, id_: &str) -> Result<Option<String>> {
+    let mut tx = conn.begin().await?;
+    let record = sqlx::query!(
+        r#"DELETE FROM refresh_token r USING "user" u
+        WHERE u.id = r.uid AND r.id = $1 AND r.expires_at > CURRENT_TIMESTAMP
+        RETURNING u.username"#,
+        id_
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(record.map(|record| record.username))
+}
+
+pub async fn delete_user_refresh_tokens(conn: &PgPool, uid_: i64) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(r#"DELETE FROM refresh_token WHERE uid = $1"#, uid_)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Adds a JWT to the denylist until it expires, along with the refresh token issued with it
+pub async fn revoke_jwt(conn: &PgPool, jti_: &str, expires_at_: DateTime<Utc>) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(r#"DELETE FROM revoked_jwt WHERE expires_at < CURRENT_TIMESTAMP"#)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO revoked_jwt (jti, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+        jti_,
+        expires_at_
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"DELETE FROM refresh_token WHERE jti = $1"#, jti_)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn add_plan(conn: &PgPool, input: &IterPlanInput) -> Result<IterPlan> {
+    let mut tx = conn.begin().await?;
+    let plan = sqlx::query_as!(
+        IterPlan,
+        r#"INSERT INTO iter_plans (title, begin_date, end_date, notes) VALUES ($1, $2, $3, $4)
+        RETURNING id, title, begin_date, end_date, notes"#,
+        input.title,
+        input.begin_date,
+        input.end_date,
+        input.notes
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(plan)
+}
+
+pub async fn update_plan(
+    conn: &PgPool,
+    id_: i64,
+    input: &IterPlanInput,
+) -> Result<Option<IterPlan>> {
+    let mut tx = conn.begin().await?;
+    let plan = sqlx::query_as!(
+        IterPlan,
+        r#"UPDATE iter_plans SET title = $1, begin_date = $2, end_date = $3, notes = $4 WHERE id = $5
+        RETURNING id, title, begin_date, end_date, notes"#,
+        input.title,
+        input.begin_date,
+        input.end_date,
+        input.notes,
+        id_
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(plan)
+}
+
+pub async fn delete_plan(conn: &PgPool, id_: i64) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(r#"DELETE FROM iter_entries WHERE plan_id = $1"#, id_)
+        .execute(&mut tx)
+        .await?;
+    let result = sqlx::query!(r#"DELETE FROM iter_plans WHERE id = $1"#, id_)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn add_entry(conn: &PgPool, plan_id_: i64, input: &IterEntryInput) -> Result<IterEntry> {
+    let mut tx = conn.begin().await?;
+    let entry = sqlx::query_as!(
+        IterEntry,
+        r#"INSERT INTO iter_entries
+        (plan_id, parent_id, name, done, date, category, version, origin, target, url, description)
+        VALUES ($1, $2, $3, $4, COALESCE($5, LOCALTIMESTAMP), $6, $7, $8, $9, $10, $11)
+        RETURNING id, plan_id, parent_id, name, done, date, category, version, origin, target, url, description"#,
+        plan_id_,
+        input.parent_id,
+        input.name,
+        input.done,
+        input.date,
+        input.category,
+        input.version,
+        input.origin,
+        input.target,
+        input.url,
+        input.description
+    )
+    .fetch_one(&mut tx)
+    .await;
+    let entry = match entry {
+        Ok(entry) => entry,
+        Err(err) => return Err(entry_date_error(conn, plan_id_, err).await),
+    };
+    tx.commit().await?;
+
+    Ok(entry)
+}
+
+pub async fn update_entry(
+    conn: &PgPool,
+    plan_id_: i64,
+    id_: i64,
+    input: &IterEntryInput,
+) -> Result<Option<IterEntry>> {
+    let mut tx = conn.begin().await?;
+    let entry = sqlx::query_as!(
+        IterEntry,
+        r#"UPDATE iter_entries SET parent_id = $1, name = $2, done = $3, date = COALESCE($4, date),
+        category = $5, version = $6, origin = $7, target = $8, url = $9, description = $10
+        WHERE id = $11 AND plan_id = $12
+        RETURNING id, plan_id, parent_id, name, done, date, category, version, origin, target, url, description"#,
+        input.parent_id,
+        input.name,
+        input.done,
+        input.date,
+        input.category,
+        input.version,
+        input.origin,
+        input.target,
+        input.url,
+        input.description,
+        id_,
+        plan_id_
+    )
+    .fetch_optional(&mut tx)
+    .await;
+    let entry = match entry {
+        Ok(entry) => entry,
+        Err(err) => return Err(entry_date_error(conn, plan_id_, err).await),
+    };
+    tx.commit().await?;
+
+    Ok(entry)
+}
+
+pub async fn delete_entry(conn: &PgPool, plan_id_: i64, id_: i64) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    let result = sqlx::query!(
+        r#"DELETE FROM iter_entries WHERE id = $1 AND plan_id = $2"#,
+        id_,
+        plan_id_
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn add_comment(
+    conn: &PgPool,
+    request_id_: i64,
+    author_id: i64,
+    body: &str,
+) -> Result<i64> {
+    let mut tx = conn.begin().await?;
+    let record = sqlx::query!(
+        r#"INSERT INTO request_comment (request_id, author_id, body) VALUES ($1, $2, $3) RETURNING id"#,
+        request_id_,
+        author_id,
+        body
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(record.id)
+}
+
+pub async fn update_comment(
+    conn: &PgPool,
+    request_id_: i64,
+    id_: i64,
+    body: &str,
+    user_id: i64,
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"UPDATE request_comment SET body = $1, edited_at = CURRENT_TIMESTAMP WHERE id = $2"#,
+        body,
+        id_
+    )
+    .execute(&mut tx)
+    .await?;
+    // Only the ID is recorded, as the history is public
+    add_request_event(
+        &mut tx,
+        request_id_,
+        user_id,
+        "comment_edited",
+        Some(&id_.to_string()),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Hides a comment. The text is kept for moderation purposes.
+pub async fn delete_comment(conn: &PgPool, request_id_: i64, id_: i64, user_id: i64) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"UPDATE request_comment SET deleted = true WHERE id = $1"#,
+        id_
+    )
+    .execute(&mut tx)
+    .await?;
+    add_request_event(
+        &mut tx,
+        request_id_,
+        user_id,
+        "comment_deleted",
+        Some(&id_.to_string()),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Updates the editable fields of a request, recording every changed field
+pub async fn update_request(
+    conn: &PgPool,
+    request: &Request,
+    input: &RequestInput,
+    user_id: i64,
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"UPDATE request SET type = $1, name = $2, description = $3, note = $4 WHERE id = $5"#,
+        input.type_,
+        input.name,
+        input.description,
+        input.note,
+        request.id
+    )
+    .execute(&mut tx)
+    .await?;
+    let changes = [
+        ("type", Some(&request.type_), Some(&input.type_)),
+        ("name", Some(&request.name), Some(&input.name)),
+        (
+            "description",
+            request.description.as_ref(),
+            input.description.as_ref(),
+        ),
+        ("note", request.note.as_ref(), input.note.as_ref()),
+    ];
+    for (field, old_value, new_value) in changes.iter() {
+        if old_value != new_value {
+            add_request_event(
+                &mut tx,
+                request.id,
+                user_id,
+                field,
+                old_value.map(|value| value.as_str()),
+                new_value.map(|value| value.as_str()),
+            )
+            .await?;
+        }
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,353 @@
+use crate::actions::{self, ActionError};
+use crate::csrf::{csrf_token, CsrfForm, NoFields};
+use crate::{auth, db, models};
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{get, http, post, web, Error, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+use yarte::Template;
+
+#[derive(Template)]
+#[template(path = "details.hbs")]
+struct DetailsTemplate {
+    base_url: String,
+    request: models::RequestStr,
+    history: Vec<models::RequestEvent>,
+    comments: Vec<CommentView>,
+    title: String,
+    banner_title: String,
+    msg: String,
+    csrf_token: String,
+    logged_in: bool,
+    can_claim: bool,
+    can_unclaim: bool,
+    can_assign: bool,
+    can_close: bool,
+    can_edit: bool,
+}
+
+struct CommentView {
+    comment: models::Comment,
+    can_edit: bool,
+    can_delete: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AssignForm {
+    packager: String,
+}
+
+async fn render_details(
+    conn: &PgPool,
+    session: &Session,
+    base_url: String,
+    id: i64,
+    user: Option<&models::User>,
+    msg: String,
+) -> Result<String, Error> {
+    let detail = db::get_request_detail_by_id(&conn, id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let history = db::get_request_history(&conn, id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let comments = db::get_comments(&conn, id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .into_iter()
+        .map(|comment| {
+            let is_author = user
+                .map(|user| user.id == comment.author_id)
+                .unwrap_or(false);
+            let is_admin = user.map(|user| user.admin).unwrap_or(false);
+            CommentView {
+                comment,
+                can_edit: is_author,
+                can_delete: is_author || is_admin,
+            }
+        })
+        .collect();
+    let request_name = detail.name.clone();
+    let is_open = detail.status == "OPEN";
+    let is_packager = match (user, detail.packager.as_ref()) {
+        (Some(user), Some(packager)) => &user.username == packager,
+        _ => false,
+    };
+    let is_admin = user.map(|user| user.admin).unwrap_or(false);
+    let response = DetailsTemplate {
+        base_url,
+        logged_in: user.is_some(),
+        can_claim: user.is_some() && is_open && detail.packager.is_none(),
+        can_unclaim: is_open && detail.packager.is_some() && (is_packager || is_admin),
+        can_assign: is_open && is_admin,
+        can_close: is_open && (is_packager || is_admin),
+        can_edit: is_open
+            && (is_packager
+                || is_admin
+                || user
+                    .map(|user| user.username == detail.requester)
+                    .unwrap_or(false)),
+        request: detail,
+        history,
+        comments,
+        banner_title: request_name.clone(),
+        title: format!("{} - AOSC OS Package Requests", request_name),
+        msg,
+        csrf_token: csrf_token(session)?,
+    };
+
+    Ok(response
+        .call()
+        .unwrap_or("Internal Server Error".to_string()))
+}
+
+/// Redirects back to the details page on success, or renders it with the error message
+async fn action_response(
+    conn: &PgPool,
+    session: &Session,
+    base_url: String,
+    id: i64,
+    user: &models::User,
+    result: Result<(), ActionError>,
+) -> Result<HttpResponse, Error> {
+    let err = match result {
+        Ok(()) => {
+            return Ok(HttpResponse::Found()
+                .header(
+                    http::header::LOCATION,
+                    format!("{}/detail/{}", base_url, id),
+                )
+                .finish())
+        }
+        Err(ActionError::NotFound(_)) => return Ok(HttpResponse::NotFound().finish()),
+        Err(err) => err,
+    };
+    let mut response = match err {
+        ActionError::Forbidden(_) => HttpResponse::Forbidden(),
+        ActionError::Conflict(_) => HttpResponse::Conflict(),
+        ActionError::Invalid(_) => HttpResponse::BadRequest(),
+        _ => HttpResponse::InternalServerError(),
+    };
+    let body = render_details(conn, session, base_url, id, Some(user), err.to_string()).await?;
+
+    Ok(response
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(body))
+}
+
+#[get("/detail/{id}")]
+pub async fn details(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let user = auth::current_user(&conn, &id).await;
+    let body = render_details(
+        &conn,
+        &session,
+        base_url,
+        (path.0).0,
+        user.as_ref(),
+        "".to_owned(),
+    )
+    .await?;
+    let res = HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(body);
+    Ok(res)
+}
+
+#[post("/detail/{id}/claim")]
+pub async fn claim(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    _form: CsrfForm<NoFields>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::claim_request(&conn, &user, request_id).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/unclaim")]
+pub async fn unclaim(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    _form: CsrfForm<NoFields>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::unclaim_request(&conn, &user, request_id).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/assign")]
+pub async fn assign(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    form: CsrfForm<AssignForm>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::assign_request(&conn, &user, request_id, &form.packager).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/close")]
+pub async fn close(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    _form: CsrfForm<NoFields>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::close_request(&conn, &user, request_id, false).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/reject")]
+pub async fn reject(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    _form: CsrfForm<NoFields>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::close_request(&conn, &user, request_id, true).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/comments")]
+pub async fn add_comment(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    form: CsrfForm<models::CommentInput>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::add_comment(&conn, &user, request_id, &form)
+            .await
+            .map(|_| ());
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/comments/{comment}/edit")]
+pub async fn edit_comment(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    form: CsrfForm<models::CommentInput>,
+    base_url: String,
+    path: web::Path<(i64, i64)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let (request_id, comment_id) = path.into_inner();
+        let result = actions::edit_comment(&conn, &user, request_id, comment_id, &form).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/comments/{comment}/delete")]
+pub async fn delete_comment(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    _form: CsrfForm<NoFields>,
+    base_url: String,
+    path: web::Path<(i64, i64)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let (request_id, comment_id) = path.into_inner();
+        let result = actions::delete_comment(&conn, &user, request_id, comment_id).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
+
+#[post("/detail/{id}/edit")]
+pub async fn edit(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    form: CsrfForm<models::RequestPatch>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    if let Some(user) = auth::current_user(&conn, &id).await {
+        let request_id = (path.0).0;
+        let result = actions::edit_request(&conn, &user, request_id, &form).await;
+        return action_response(&conn, &session, base_url, request_id, &user, result).await;
+    }
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/login")
+        .finish())
+}
@@ -0,0 +1,216 @@
+//! Server-side sessions, referenced by identity cookies that stay readable while their keys are rotated
+use crate::db;
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+use actix_web::cookie::SameSite;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{error, Error, HttpRequest};
+use chrono::{Duration, Utc};
+use log::warn;
+use rand::RngCore;
+use ring::digest;
+use sqlx::PgPool;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Sessions idle for longer than this are logged out
+const SESSION_MAX_IDLE_DAYS: i64 = 30;
+/// The last-seen time is only updated after this many minutes, to spare the database
+const SESSION_TOUCH_MINUTES: i64 = 5;
+
+/// Marks requests whose identity cookie was sealed with an outdated key
+struct OutdatedKey;
+
+/// Reads identity cookies with any of the configured keys, and seals new ones with the newest.
+/// Cookies sealed with an older key are reissued with the newest one.
+pub struct RotatingIdentityPolicy {
+    /// Newest first
+    policies: Vec<CookieIdentityPolicy>,
+}
+
+impl RotatingIdentityPolicy {
+    pub fn new(keys: &[Vec<u8>]) -> Self {
+        RotatingIdentityPolicy {
+            policies: keys
+                .iter()
+                .map(|key| {
+                    // Lax still sends the cookie when coming back from the OAuth providers
+                    CookieIdentityPolicy::new(key)
+                        .name("identity")
+                        .secure(true)
+                        .same_site(SameSite::Lax)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl IdentityPolicy for RotatingIdentityPolicy {
+    type Future = Pin<Box<dyn Future<Output = Result<Option<String>, Error>>>>;
+    type ResponseFuture = Pin<Box<dyn Future<Output = Result<(), Error>>>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        // The cookie policies resolve immediately, so they are all asked up front
+        let lookups = self
+            .policies
+            .iter()
+            .map(|policy| policy.from_request(req))
+            .collect::<Vec<_>>();
+        let request = req.request().clone();
+
+        Box::pin(async move {
+            for (index, lookup) in lookups.into_iter().enumerate() {
+                if let Some(identity) = lookup.await? {
+                    if index > 0 {
+                        request.extensions_mut().insert(OutdatedKey);
+                    }
+                    return Ok(Some(identity));
+                }
+            }
+
+            Ok(None)
+        })
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let outdated = res.request().extensions().get::<OutdatedKey>().is_some();
+        let future = self.policies[0].to_response(identity, changed || outdated, res);
+
+        Box::pin(future)
+    }
+}
+
+/// The session of the current request
+#[derive(Clone)]
+struct CurrentSession {
+    /// Sent in the cookie
+    token: String,
+    /// Stored in the database
+    id: String,
+}
+
+#[inline]
+fn session_id(token: &str) -> String {
+    hex::encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}
+
+/// Returns the ID of the session the request was made with, as listed on the account page
+pub fn current_session(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<CurrentSession>()
+        .map(|session| session.id.clone())
+}
+
+/// Keeps the logins in the `user_session` table, so that they can be listed and revoked.
+/// The identity cookie only holds a random token, whose hash is the ID of the session.
+pub struct SessionIdentityPolicy {
+    cookies: RotatingIdentityPolicy,
+    pool: PgPool,
+}
+
+impl SessionIdentityPolicy {
+    pub fn new(cookies: RotatingIdentityPolicy, pool: PgPool) -> Self {
+        SessionIdentityPolicy { cookies, pool }
+    }
+}
+
+impl IdentityPolicy for SessionIdentityPolicy {
+    type Future = Pin<Box<dyn Future<Output = Result<Option<String>, Error>>>>;
+    type ResponseFuture = Pin<Box<dyn Future<Output = Result<(), Error>>>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        let lookup = self.cookies.from_request(req);
+        let request = req.request().clone();
+        let pool = self.pool.clone();
+
+        Box::pin(async move {
+            let token = match lookup.await? {
+                Some(token) => token,
+                None => return Ok(None),
+            };
+            let id = session_id(&token);
+            let seen_after = Utc::now() - Duration::days(SESSION_MAX_IDLE_DAYS);
+            let (username, last_seen) = match db::get_session_user(&pool, &id, seen_after).await {
+                Ok(Some(session)) => session,
+                Ok(None) => return Ok(None),
+                Err(err) => {
+                    warn!("Failed to look up session: {}", err);
+                    return Ok(None);
+                }
+            };
+            if Utc::now() - last_seen > Duration::minutes(SESSION_TOUCH_MINUTES) {
+                if let Err(err) = db::touch_session(&pool, &id).await {
+                    warn!("Failed to update session: {}", err);
+                }
+            }
+            request
+                .extensions_mut()
+                .insert(CurrentSession { token, id });
+
+            Ok(Some(username))
+        })
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let current = res.request().extensions().get::<CurrentSession>().cloned();
+        if !changed {
+            // Lets the cookies reissue the token if it was sealed with an outdated key
+            let token = current.map(|session| session.token);
+            return self.cookies.to_response(token, false, res);
+        }
+        let pool = self.pool.clone();
+        let new_session = identity.map(|username| {
+            let mut token = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut token);
+            let token = base64::encode_config(&token, base64::URL_SAFE_NO_PAD);
+            let request = res.request();
+            let user_agent = request
+                .headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let ip = request
+                .connection_info()
+                .realip_remote_addr()
+                .map(str::to_string);
+            (username, token, user_agent, ip)
+        });
+        let cookie = self.cookies.to_response(
+            new_session.as_ref().map(|(_, token, _, _)| token.clone()),
+            true,
+            res,
+        );
+
+        Box::pin(async move {
+            cookie.await?;
+            // Logging in again or out ends the current session
+            if let Some(current) = current {
+                db::delete_session(&pool, &current.id)
+                    .await
+                    .map_err(error::ErrorInternalServerError)?;
+            }
+            if let Some((username, token, user_agent, ip)) = new_session {
+                db::add_session(
+                    &pool,
+                    &session_id(&token),
+                    &username,
+                    user_agent.as_deref(),
+                    ip.as_deref(),
+                )
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+            }
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,175 @@
+//! In-process cache of the JSON Web Key Sets used to validate tokens from the providers
+use anyhow::{anyhow, Result};
+use awc::Client as awcClient;
+use jsonwebtoken::DecodingKey;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Used when the response does not say how long it can be cached
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+/// Upper bound for `max-age`, so that revoked keys do not stay around forever
+const MAX_MAX_AGE: Duration = Duration::from_secs(86400);
+/// Minimum interval between refetches, whatever the key set says about caching
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Clone)]
+struct JWKEntry {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct JWK {
+    keys: Vec<JWKEntry>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    keys: HashMap<String, JWKEntry>,
+    expires_at: Option<Instant>,
+    last_fetch: Option<Instant>,
+}
+
+pub struct JwksCache {
+    url: String,
+    state: Mutex<CacheState>,
+}
+
+/// Reads `max-age` from the `Cache-Control` header; `no-cache` and `no-store` mean no caching
+fn parse_max_age(cache_control: Option<&str>) -> Duration {
+    let cache_control = match cache_control {
+        Some(cache_control) => cache_control,
+        None => return DEFAULT_MAX_AGE,
+    };
+    let mut max_age = DEFAULT_MAX_AGE;
+    for directive in cache_control.split(',').map(str::trim) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-cache" || directive == "no-store" {
+            return Duration::from_secs(0);
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=") {
+            if let Ok(seconds) = seconds.trim_matches('"').parse::<u64>() {
+                max_age = Duration::from_secs(seconds);
+            }
+        }
+    }
+
+    max_age.min(MAX_MAX_AGE)
+}
+
+impl JwksCache {
+    pub fn new(url: String) -> Self {
+        JwksCache {
+            url,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Returns the key with the given ID. The key set is refetched when it has expired,
+    /// or when the key is unknown, but never more often than `MIN_REFETCH_INTERVAL`,
+    /// even if the key set cannot be cached at all.
+    /// Stale keys keep being used while the key set cannot be fetched.
+    pub async fn key(&self, kid: &str) -> Result<DecodingKey<'static>> {
+        let now = Instant::now();
+        {
+            let mut state = self.state.lock().unwrap();
+            let fresh = state.expires_at.map(|at| at > now).unwrap_or(false);
+            let recently = state
+                .last_fetch
+                .map(|at| now.duration_since(at) < MIN_REFETCH_INTERVAL)
+                .unwrap_or(false);
+            match state.keys.get(kid).map(decoding_key) {
+                Some(key) if fresh => return Ok(key),
+                cached if recently => return cached.ok_or(anyhow!("Unknown key ID: {}", kid)),
+                _ => (),
+            }
+            // Claimed before fetching so that concurrent logins do not all refetch
+            state.last_fetch = Some(now);
+        }
+
+        match self.fetch().await {
+            Ok((keys, max_age)) => {
+                let mut state = self.state.lock().unwrap();
+                state.keys = keys;
+                state.expires_at = Some(Instant::now() + max_age);
+                info!("JWKS refreshed from {}", self.url);
+            }
+            Err(err) => warn!("Failed to fetch JWKS from {}: {}", self.url, err),
+        }
+        let state = self.state.lock().unwrap();
+
+        state
+            .keys
+            .get(kid)
+            .map(decoding_key)
+            .ok_or(anyhow!("Unknown key ID: {}", kid))
+    }
+
+    async fn fetch(&self) -> Result<(HashMap<String, JWKEntry>, Duration)> {
+        let mut resp = awcClient::default()
+            .get(&self.url)
+            .send()
+            .await
+            .map_err(|_| anyhow!("Failed to send JWK request"))?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("JWK endpoint returned {}", resp.status()));
+        }
+        let max_age = parse_max_age(
+            resp.headers()
+                .get(actix_web::http::header::CACHE_CONTROL)
+                .and_then(|value| value.to_str().ok()),
+        );
+        let jwk = resp.json::<JWK>().await?;
+        let keys = jwk
+            .keys
+            .into_iter()
+            .map(|key| (key.kid.clone(), key))
+            .collect();
+
+        Ok((keys, max_age))
+    }
+}
+
+#[inline]
+fn decoding_key(key: &JWKEntry) -> DecodingKey<'static> {
+    DecodingKey::from_rsa_components(&key.n, &key.e).into_static()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_age_is_read() {
+        assert_eq!(
+            parse_max_age(Some("public, max-age=600")),
+            Duration::from_secs(600)
+        );
+        assert_eq!(parse_max_age(Some("max-age=999999")), MAX_MAX_AGE);
+    }
+
+    #[test]
+    fn no_cache_and_no_store_disable_caching() {
+        assert_eq!(
+            parse_max_age(Some("no-cache, max-age=600")),
+            Duration::from_secs(0)
+        );
+        assert_eq!(parse_max_age(Some("No-Store")), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn missing_header_uses_default() {
+        assert_eq!(parse_max_age(None), DEFAULT_MAX_AGE);
+        assert_eq!(parse_max_age(Some("public")), DEFAULT_MAX_AGE);
+    }
+
+    #[test]
+    fn malformed_max_age_uses_default() {
+        assert_eq!(parse_max_age(Some("max-age=soon")), DEFAULT_MAX_AGE);
+        assert_eq!(parse_max_age(Some("max-age=-1")), DEFAULT_MAX_AGE);
+    }
+}
@@ -0,0 +1,138 @@
+//! Keys of the identity and session cookies, shared by all instances and kept across restarts
+use anyhow::{anyhow, Result};
+use ring::hmac;
+
+/// Keys shorter than this are rejected; the cookie keys are derived from them
+const MIN_KEY_LENGTH: usize = 32;
+
+#[derive(Clone)]
+pub struct SessionKeys {
+    /// Newest first
+    keys: Vec<Vec<u8>>,
+}
+
+impl SessionKeys {
+    /// Reads base64-encoded keys, newest first, from `SESSION_KEYS` (comma-separated)
+    /// or from the file at `SESSION_KEY_FILE` (one key per line, `#` starts a comment).
+    pub fn from_env() -> Result<Self> {
+        let keys = std::env::var("SESSION_KEYS")
+            .ok()
+            .filter(|keys| !keys.trim().is_empty());
+        let keys = if let Some(keys) = keys {
+            parse_keys(keys.split(','))?
+        } else if let Ok(path) = std::env::var("SESSION_KEY_FILE") {
+            parse_key_file(&std::fs::read_to_string(path)?)?
+        } else {
+            return Err(anyhow!(
+                "SESSION_KEYS or SESSION_KEY_FILE must be set, generate a key with `openssl rand -base64 32`"
+            ));
+        };
+
+        Ok(SessionKeys { keys })
+    }
+
+    /// Keys for the identity cookie; the first one is used for new cookies
+    pub fn identity_keys(&self) -> Vec<Vec<u8>> {
+        self.keys
+            .iter()
+            .map(|key| derive(key, "identity"))
+            .collect()
+    }
+
+    /// Key for the session cookie, which only holds short-lived state
+    pub fn session_key(&self) -> Vec<u8> {
+        derive(&self.keys[0], "session")
+    }
+}
+
+/// One key per line, `#` starts a comment
+fn parse_key_file(content: &str) -> Result<Vec<Vec<u8>>> {
+    parse_keys(
+        content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("")),
+    )
+}
+
+fn parse_keys<'a>(keys: impl Iterator<Item = &'a str>) -> Result<Vec<Vec<u8>>> {
+    let mut decoded = Vec::new();
+    for key in keys.map(str::trim).filter(|key| !key.is_empty()) {
+        let key = base64::decode(key).map_err(|_| anyhow!("Session key is not valid base64"))?;
+        if key.len() < MIN_KEY_LENGTH {
+            return Err(anyhow!(
+                "Session keys must be at least {} bytes long",
+                MIN_KEY_LENGTH
+            ));
+        }
+        decoded.push(key);
+    }
+    if decoded.is_empty() {
+        return Err(anyhow!("No session key configured"));
+    }
+
+    Ok(decoded)
+}
+
+/// Derives a separate key for each cookie from the configured key
+#[inline]
+fn derive(key: &[u8], purpose: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, purpose.as_bytes()).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> String {
+        base64::encode(&[byte; MIN_KEY_LENGTH])
+    }
+
+    #[test]
+    fn short_or_invalid_keys_are_rejected() {
+        let short = base64::encode(&[1u8; MIN_KEY_LENGTH - 1]);
+        assert!(parse_keys(vec![short.as_str()].into_iter()).is_err());
+        assert!(parse_keys(vec!["not base64!"].into_iter()).is_err());
+        assert!(parse_keys(vec!["", " "].into_iter()).is_err());
+    }
+
+    #[test]
+    fn key_file_skips_comments_and_blank_lines() {
+        let content = format!(
+            "# Rotated on 2026-10-17\n\n{}  # current\n   \n# {}\n{}\n",
+            key(1),
+            key(9),
+            key(2)
+        );
+        let keys = parse_key_file(&content).unwrap();
+        assert_eq!(
+            keys,
+            vec![vec![1u8; MIN_KEY_LENGTH], vec![2u8; MIN_KEY_LENGTH]]
+        );
+    }
+
+    #[test]
+    fn keys_stay_newest_first() {
+        let (first, second) = (key(1), key(2));
+        let keys = SessionKeys {
+            keys: parse_keys(vec![first.as_str(), second.as_str()].into_iter()).unwrap(),
+        };
+        let identity_keys = keys.identity_keys();
+        assert_eq!(identity_keys.len(), 2);
+        assert_eq!(identity_keys[0], derive(&[1u8; MIN_KEY_LENGTH], "identity"));
+        assert_eq!(identity_keys[1], derive(&[2u8; MIN_KEY_LENGTH], "identity"));
+        assert_eq!(
+            keys.session_key(),
+            derive(&[1u8; MIN_KEY_LENGTH], "session")
+        );
+    }
+
+    #[test]
+    fn purposes_get_different_keys() {
+        let keys = SessionKeys {
+            keys: vec![vec![1u8; MIN_KEY_LENGTH]],
+        };
+        assert_ne!(keys.identity_keys()[0], keys.session_key());
+        assert_ne!(keys.session_key(), vec![1u8; MIN_KEY_LENGTH]);
+    }
+}
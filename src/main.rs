@@ -1,22 +1,35 @@
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_identity::IdentityService;
 use actix_session::CookieSession;
+use actix_web::cookie::SameSite;
 use actix_web::HttpResponse;
 use actix_web::{get, head, http, middleware, web, App, Error, HttpServer, Responder};
 use dotenv;
+use identity::{RotatingIdentityPolicy, SessionIdentityPolicy};
+use keys::SessionKeys;
 use log::info;
 use middleware::normalize::TrailingSlash;
-use oauth2::basic::BasicClient;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
+use providers::OauthProviders;
+use signing::JwtKeys;
 use sqlx::PgPool;
-use rand::RngCore;
 use yarte::Template;
 
+mod actions;
+mod admin;
 mod assets;
 mod auth;
+mod csrf;
 mod db;
+mod details;
+mod identity;
+mod jwks;
+mod keys;
 mod models;
 mod oauth;
+mod plans;
+mod providers;
 mod rest;
+mod signing;
+mod tokens;
 
 #[derive(Template)]
 #[template(path = "404.hbs")]
@@ -35,40 +48,12 @@ struct IndexTemplate {
 }
 
 #[derive(Template)]
-#[template(path = "details.hbs")]
-struct DetailsTemplate {
+#[template(path = "search.hbs")]
+struct SearchTemplate {
     base_url: String,
-    request: models::RequestStr,
-    title: String,
-    banner_title: String,
-}
-
-#[get("/detail/{id}")]
-async fn details(
-    pool: web::Data<PgPool>,
-    base_url: String,
-    path: web::Path<(i64,)>,
-) -> Result<HttpResponse, Error> {
-    let conn = pool.get_ref();
-
-    let detail = db::get_request_detail_by_id(&conn, (path.0).0)
-        .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
-    let request_name = detail.name.clone();
-    let response = DetailsTemplate {
-        base_url,
-        request: detail,
-        banner_title: request_name.clone(),
-        title: format!("{} - AOSC OS Package Requests", request_name),
-    };
-    let res = HttpResponse::Ok()
-        .header(http::header::CONTENT_TYPE, "text/html")
-        .body(
-            response
-                .call()
-                .unwrap_or("Internal Server Error".to_string()),
-        );
-    Ok(res)
+    query: String,
+    results: Vec<models::SearchResult>,
+    banner_subtitle: String,
 }
 
 #[head("/")]
@@ -100,6 +85,37 @@ async fn index(pool: web::Data<PgPool>, base_url: String) -> Result<HttpResponse
     Ok(res)
 }
 
+#[get("/search")]
+async fn search(
+    pool: web::Data<PgPool>,
+    base_url: String,
+    query: web::Query<models::SearchQuery>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let query = query.into_inner();
+    let results = if query.q.trim().is_empty() {
+        vec![]
+    } else {
+        db::search_requests(&conn, &query.q, models::DEFAULT_PAGE_SIZE)
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?
+    };
+    let response = SearchTemplate {
+        base_url,
+        banner_subtitle: format!("{} matching requests", results.len()),
+        query: query.q,
+        results,
+    };
+    let res = HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            response
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        );
+    Ok(res)
+}
+
 async fn not_found() -> impl Responder {
     HttpResponse::NotFound()
         .header(http::header::CONTENT_TYPE, "text/html")
@@ -119,66 +135,82 @@ async fn main() -> std::io::Result<()> {
     let connspec = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
     let listen = std::env::var("LISTEN_ADDRESS").expect("LISTEN_ADDRESS not set");
     let base_url = std::env::var("BASE_URL").expect("BASE_URL not set");
-    std::env::var("JWT_SECRET").expect("JWT_SECRET not set"); // will be used later
-    std::env::var("OAUTH_JWK_URL").expect("OAUTH_JWK_URL not set"); // will be used later
-    let oauth_client = std::env::var("OAUTH_CLIENT_ID").expect("OAUTH_CLIENT_ID not set");
-    let oauth_secret = std::env::var("OAUTH_SECRET").expect("OAUTH_SECRET not set");
-    let oauth_auth_url = std::env::var("OAUTH_AUTH_URL").expect("OAUTH_URL not set");
-    let oauth_token_url = std::env::var("OAUTH_TOKEN_URL").expect("OAUTH_URL not set");
     let pool = PgPool::connect(&connspec)
         .await
         .expect("Unable to connect to database.");
     info!("Database connection established.");
-    let oauth = BasicClient::new(
-        ClientId::new(oauth_client),
-        Some(ClientSecret::new(oauth_secret)),
-        AuthUrl::new(oauth_auth_url).expect("OAUTH_URL malformed"),
-        Some(TokenUrl::new(oauth_token_url).unwrap()),
-    )
-    .set_redirect_uri(RedirectUrl::new(format!("{}/oauth/aosc/", base_url)).unwrap());
-    let mut rng = rand::thread_rng();
-    let mut id_key: [u8; 32] = [0; 32];
-    let mut csrf_key: [u8; 32] = [0; 32];
-    rng.fill_bytes(&mut id_key);
-    rng.fill_bytes(&mut csrf_key);
+    let providers =
+        OauthProviders::from_env(&base_url).expect("Invalid OAuth provider configuration");
+    let keys = SessionKeys::from_env().expect("Invalid session keys");
+    let jwt_keys = JwtKeys::from_env().expect("Invalid JWT signing keys");
 
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default()) // enable logger
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&id_key)
-                    .name("identity")
-                    .secure(true),
-            ))
+            .wrap(IdentityService::new(SessionIdentityPolicy::new(
+                RotatingIdentityPolicy::new(&keys.identity_keys()),
+                pool.clone(),
+            )))
             .wrap(
-                CookieSession::private(&csrf_key)
+                CookieSession::private(&keys.session_key())
                     .name("csrf")
                     .path("/")
-                    .secure(true))
+                    .secure(true)
+                    .same_site(SameSite::Lax),
+            )
             .wrap(middleware::NormalizePath::new(TrailingSlash::Trim))
             .data(pool.clone())
             .data(base_url.clone())
-            .data(oauth.clone())
+            .data(providers.clone())
+            .data(jwt_keys.clone())
             // traditional pages
             .service(ping)
             .service(index)
-            .service(details)
+            .service(search)
+            .service(details::details)
+            .service(details::claim)
+            .service(details::unclaim)
+            .service(details::assign)
+            .service(details::close)
+            .service(details::reject)
+            .service(details::edit)
+            .service(details::add_comment)
+            .service(details::edit_comment)
+            .service(details::delete_comment)
+            .service(plans::plans)
+            .service(plans::plan)
             .service(auth::login)
             .service(auth::form_login)
             .service(auth::logout)
+            .service(auth::register)
+            .service(auth::form_register)
             .service(auth::account_panel)
             .service(auth::form_account)
+            .service(auth::revoke_session)
+            .service(auth::create_token)
+            .service(auth::revoke_token)
+            .service(admin::admin_panel)
+            .service(admin::form_user)
+            .service(admin::form_requests)
             // static files
             .service(assets::logo_png)
             .service(assets::logo_svg)
             .service(assets::style_css)
             // RESTful APIs
+            .service(signing::jwks)
             .route("/api/{endpoint:.*}", web::get().to(rest::rest_dispatch))
+            .route("/api/{endpoint:.*}", web::post().to(rest::rest_dispatch))
+            .route("/api/{endpoint:.*}", web::put().to(rest::rest_dispatch))
+            .route("/api/{endpoint:.*}", web::patch().to(rest::rest_dispatch))
+            .route("/api/{endpoint:.*}", web::delete().to(rest::rest_dispatch))
             // OAuth handlers
-            .route("/oauth/telegram", web::post().to(oauth::oauth_telegram))
-            .service(oauth::oauth_aosc)
-            .service(oauth::oauth_aosc_new)
-            .service(oauth::oauth_aosc_unlink)
+            .service(oauth::oauth_telegram)
+            .service(oauth::oauth_telegram_unlink)
+            .service(oauth::oauth_provider)
+            .service(oauth::oauth_provider_callback)
+            .service(oauth::oauth_provider_new)
+            .service(oauth::oauth_provider_login)
+            .service(oauth::oauth_provider_unlink)
             .default_service(web::route().to(not_found))
     })
     .bind(listen)?
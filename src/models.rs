@@ -1,6 +1,6 @@
 #![allow(unused)]
 
-use chrono::NaiveDate;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 // Generated by diesel_ext
@@ -13,7 +13,7 @@ pub struct Oauth {
     pub token: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, sqlx::FromRow)]
 pub struct Request {
     pub id: i64,
     pub status: String,
@@ -41,6 +41,63 @@ pub struct RequestStr {
     pub note: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct RequestEvent {
+    pub id: i64,
+    pub request_id: i64,
+    pub user: Option<String>,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Comment {
+    pub id: i64,
+    pub request_id: i64,
+    pub author_id: i64,
+    pub author: String,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+/// A login to the web pages
+#[derive(Debug, Serialize)]
+pub struct UserSession {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A personal access token; the token itself is only shown once, when it is created
+#[derive(Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub id: i64,
+    pub status: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub pub_date: NaiveDate,
+    pub rank: f32,
+    /// HTML-escaped name with matches wrapped in `<mark>`
+    pub name_headline: String,
+    /// HTML-escaped excerpt of the description with matches wrapped in `<mark>`
+    pub description_headline: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct User {
     pub id: i64,
@@ -49,6 +106,49 @@ pub struct User {
     pub admin: bool,
     #[serde(skip_serializing)]
     pub password_hash: Option<String>,
+    #[serde(skip_serializing)]
+    pub locked: bool,
+    #[serde(skip_serializing)]
+    pub approved: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IterPlan {
+    pub id: i64,
+    pub title: String,
+    pub begin_date: NaiveDateTime,
+    pub end_date: NaiveDateTime,
+    pub notes: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IterEntry {
+    pub id: i64,
+    pub plan_id: i64,
+    pub parent_id: Option<i64>,
+    pub name: String,
+    pub done: bool,
+    pub date: NaiveDateTime,
+    pub category: String,
+    pub version: String,
+    pub origin: String,
+    pub target: String,
+    pub url: String,
+    pub description: String,
+}
+
+impl IterEntry {
+    /// The URL, if it is safe to link to
+    pub fn web_url(&self) -> Option<&str> {
+        Some(self.url.as_str()).filter(|url| is_web_url(url))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct IterEntryNode {
+    #[serde(flatten)]
+    pub entry: IterEntry,
+    pub children: Vec<IterEntryNode>,
 }
 
 // de
@@ -60,3 +160,282 @@ pub struct RequestInput {
     pub description: Option<String>,
     pub note: Option<String>,
 }
+
+/// Partial update of a request. Empty descriptions and notes are cleared.
+#[derive(Debug, Deserialize)]
+pub struct RequestPatch {
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub note: Option<String>,
+}
+
+impl RequestPatch {
+    /// Applies the changes on top of `request`, yielding the complete new fields
+    pub fn apply(&self, request: &Request) -> RequestInput {
+        let clear_empty = |value: &String| Some(value.clone()).filter(|value| !value.is_empty());
+        RequestInput {
+            type_: self.type_.clone().unwrap_or_else(|| request.type_.clone()),
+            name: self.name.clone().unwrap_or_else(|| request.name.clone()),
+            description: match &self.description {
+                Some(description) => clear_empty(description),
+                None => request.description.clone(),
+            },
+            note: match &self.note {
+                Some(note) => clear_empty(note),
+                None => request.note.clone(),
+            },
+        }
+    }
+}
+
+pub const REQUEST_STATUSES: [&str; 3] = ["OPEN", "DONE", "REJECTED"];
+pub const REQUEST_TYPES: [&str; 3] = ["PAKREQ", "UPDREQ", "OPTREQ"];
+
+impl RequestInput {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !REQUEST_TYPES.contains(&self.type_.as_str()) {
+            return Err("Request type must be one of PAKREQ, UPDREQ or OPTREQ");
+        }
+        if self.name.is_empty() || self.name.contains(char::is_whitespace) {
+            return Err("Package name must not be empty or contain whitespaces");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IterPlanInput {
+    pub title: String,
+    pub begin_date: NaiveDateTime,
+    pub end_date: NaiveDateTime,
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl IterPlanInput {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.title.trim().is_empty() {
+            return Err("Plan title must not be empty");
+        }
+        if self.begin_date > self.end_date {
+            return Err("Plan must not end before it begins");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IterEntryInput {
+    pub parent_id: Option<i64>,
+    pub name: String,
+    #[serde(default)]
+    pub done: bool,
+    pub date: Option<NaiveDateTime>,
+    pub category: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub origin: String,
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+pub const MAX_URL_LENGTH: usize = 2048;
+
+/// Only `http` and `https` URLs are linked, as others like `javascript:` could run scripts
+pub fn is_web_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+impl IterEntryInput {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.name.trim().is_empty() {
+            return Err("Entry name must not be empty");
+        }
+        if self.category.trim().is_empty() {
+            return Err("Entry category must not be empty");
+        }
+        if !self.url.is_empty() {
+            if self.url.len() > MAX_URL_LENGTH {
+                return Err("Entry URL must not be longer than 2048 characters");
+            }
+            if !is_web_url(&self.url) {
+                return Err("Entry URL must start with http:// or https://");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestFilter {
+    /// One of `REQUEST_STATUSES`, or `all`. Defaults to `OPEN`.
+    pub status: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub requester: Option<String>,
+    pub packager: Option<String>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    /// Substring of the package name, case-insensitive
+    pub name: Option<String>,
+    /// One of `id`, `date` or `name`. Defaults to `id`.
+    pub sort: Option<String>,
+    /// `asc` or `desc`. Defaults to `desc`.
+    pub order: Option<String>,
+    pub limit: Option<i64>,
+    /// ID of the last request of the previous page
+    pub cursor: Option<i64>,
+}
+
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+impl RequestFilter {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self.status.as_deref() {
+            Some(status) if status != "all" && !REQUEST_STATUSES.contains(&status) => {
+                return Err("Status must be one of OPEN, DONE, REJECTED or all");
+            }
+            _ => (),
+        }
+        match self.type_.as_deref() {
+            Some(type_) if !REQUEST_TYPES.contains(&type_) => {
+                return Err("Request type must be one of PAKREQ, UPDREQ or OPTREQ");
+            }
+            _ => (),
+        }
+        match self.sort.as_deref() {
+            None | Some("id") | Some("date") | Some("name") => (),
+            _ => return Err("Sort key must be one of id, date or name"),
+        }
+        match self.order.as_deref() {
+            None | Some("asc") | Some("desc") => (),
+            _ => return Err("Sort order must be either asc or desc"),
+        }
+        match self.limit {
+            Some(limit) if limit < 1 || limit > MAX_PAGE_SIZE => {
+                return Err("Limit must be between 1 and 500");
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Status to filter on, `None` if requests of all statuses are wanted
+    pub fn status(&self) -> Option<&str> {
+        match self.status.as_deref() {
+            Some("all") => None,
+            Some(status) => Some(status),
+            None => Some("OPEN"),
+        }
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommentInput {
+    pub body: String,
+}
+
+pub const MAX_COMMENT_LENGTH: usize = 10000;
+
+impl CommentInput {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.body.trim().is_empty() {
+            return Err("Comment must not be empty");
+        }
+        if self.body.chars().count() > MAX_COMMENT_LENGTH {
+            return Err("Comment must not be longer than 10000 characters");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterInput {
+    pub username: String,
+    pub password: String,
+}
+
+impl RegisterInput {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let mut chars = self.username.chars();
+        if !chars
+            .next()
+            .map(|c| c.is_ascii_lowercase())
+            .unwrap_or(false)
+        {
+            return Err("Username must start with a lowercase letter");
+        }
+        if !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+            return Err("Username may only contain lowercase letters, digits, - and _");
+        }
+        if self.username.len() > 32 {
+            return Err("Username must not be longer than 32 characters");
+        }
+        if self.password.chars().count() < 8 {
+            return Err("Password must be at least 8 characters long");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_input(url: &str) -> IterEntryInput {
+        IterEntryInput {
+            parent_id: None,
+            name: "gcc".to_string(),
+            done: false,
+            date: None,
+            category: "Toolchain".to_string(),
+            version: String::new(),
+            origin: String::new(),
+            target: String::new(),
+            url: url.to_string(),
+            description: String::new(),
+        }
+    }
+
+    #[test]
+    fn entry_accepts_web_urls() {
+        assert!(entry_input("").validate().is_ok());
+        assert!(entry_input("https://gcc.gnu.org/").validate().is_ok());
+        assert!(entry_input("HTTP://example.org").validate().is_ok());
+    }
+
+    #[test]
+    fn entry_rejects_other_urls() {
+        assert!(entry_input("javascript:alert(1)").validate().is_err());
+        assert!(entry_input(" javascript:alert(1)").validate().is_err());
+        assert!(entry_input("data:text/html,<script>").validate().is_err());
+        let long = format!("https://example.org/{}", "a".repeat(MAX_URL_LENGTH));
+        assert!(entry_input(&long).validate().is_err());
+    }
+}
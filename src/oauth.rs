@@ -1,223 +1,448 @@
-use crate::{db, BAD_REQUEST, INTERNAL_ERROR};
+use crate::auth::{current_user, login_page, PendingOauth, IDENTITY_LINKED, PENDING_OAUTH_KEY};
+use crate::csrf::CsrfForm;
+use crate::providers::{OauthProvider, OauthProviders};
+use crate::{db, BAD_REQUEST, INTERNAL_ERROR, NOT_AUTHORIZED};
 use crate::{
     models::Oauth,
     rest::{BAD_REQUEST_RETURN, INTERNAL_ERR_RESPONSE, NOT_AUTHORIZED_RESPONSE},
 };
 use actix_identity::Identity;
 use actix_session::Session;
-use actix_web::{get, http, web, Error, HttpRequest, HttpResponse};
+use actix_web::{get, http, post, web, Error, HttpResponse};
 use anyhow::{anyhow, Result};
-use awc::Client as awcClient;
-use base64::STANDARD_NO_PAD;
 use chrono::{DateTime, Utc};
-use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use log::info;
-use oauth2::{
-    basic::BasicErrorResponseType,
-    basic::{BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenType},
-    AuthorizationCode, Client, CsrfToken, EmptyExtraTokenFields, Scope, StandardErrorResponse,
-    StandardRevocableToken, StandardTokenResponse,
-};
-use oauth2::{reqwest::async_http_client, TokenResponse};
+use oauth2::{reqwest::async_http_client, AuthorizationCode, CsrfToken, Scope};
+use ring::{digest, hmac};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+/// How long the data signed by the Telegram Login Widget stays valid, in seconds
+const TELEGRAM_AUTH_MAX_AGE: i64 = 86400;
 
 #[derive(Deserialize)]
 pub struct OauthRemovalRequest {
     oid: String,
 }
 
+/// Kept in the session between the authorization request and the callback
+#[derive(Serialize, Deserialize)]
+struct AuthorizationState {
+    csrf_token: String,
+    nonce: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct OauthCallback {
     code: String,
     state: String,
 }
 
-#[derive(Deserialize)]
-struct JWKEntry {
-    kid: String,
-    n: String,
-    e: String,
+/// Links a verified external identity to the logged in user, or logs in the user it belongs to.
+/// Unknown identities are kept in the session so the user can register or link an account.
+async fn oauth_complete(
+    conn: &PgPool,
+    id: &Identity,
+    session: &Session,
+    type_: &str,
+    oid: String,
+    username: Option<String>,
+    base_url: String,
+    providers: &OauthProviders,
+) -> Result<HttpResponse, Error> {
+    if let Some(user) = current_user(conn, id).await {
+        let owner = db::get_user_by_oid(conn, type_, &oid)
+            .await
+            .map_err(|_| INTERNAL_ERROR!())?;
+        match owner {
+            Some(owner) if owner.id != user.id => {
+                return Ok(HttpResponse::Conflict()
+                    .header(http::header::CONTENT_TYPE, "text/html")
+                    .body(IDENTITY_LINKED))
+            }
+            Some(_) => {
+                return Ok(HttpResponse::Found()
+                    .header(http::header::LOCATION, "/account")
+                    .finish())
+            }
+            None => (),
+        }
+        db::add_oauth_info(
+            conn,
+            Oauth {
+                uid: user.id,
+                type_: type_.to_string(),
+                oid: Some(oid),
+                token: None,
+            },
+        )
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+        info!("{} account added: {}", type_, user.username);
+        return Ok(HttpResponse::Found()
+            .header(http::header::LOCATION, "/account")
+            .body("Authentication successful. Let's heading home..."));
+    }
+
+    let user = db::get_user_by_oid(conn, type_, &oid)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    match user {
+        Some(user) if user.locked || !user.approved => login_page(
+            session,
+            base_url,
+            providers,
+            "This account is locked or waiting for approval by an admin",
+        ),
+        Some(user) => {
+            info!("{} login: {}", type_, user.username);
+            id.remember(user.username);
+            Ok(HttpResponse::Found()
+                .header(http::header::LOCATION, "/account")
+                .body("Authentication successful. Let's heading home..."))
+        }
+        None => {
+            // Not linked to anyone yet: let the user register a new account or
+            // log in to an existing one, which picks up the identity from the session
+            session.set(
+                PENDING_OAUTH_KEY,
+                PendingOauth {
+                    type_: type_.to_string(),
+                    oid,
+                    username,
+                },
+            )?;
+            Ok(HttpResponse::Found()
+                .header(http::header::LOCATION, "/register")
+                .finish())
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct JWK {
-    keys: Vec<JWKEntry>,
+async fn oauth_unlink(
+    conn: &PgPool,
+    id: &Identity,
+    type_: &str,
+    oid: String,
+) -> Result<HttpResponse, Error> {
+    let user = match current_user(conn, id).await {
+        Some(user) => user,
+        None => return Ok(BAD_REQUEST!()),
+    };
+    db::delete_oauth_info(
+        conn,
+        Oauth {
+            uid: user.id,
+            type_: type_.to_string(),
+            oid: Some(oid),
+            token: None,
+        },
+    )
+    .await
+    .map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, "/account")
+        .finish())
 }
 
-#[derive(Deserialize, Debug)]
-struct DexClaim {
-    sub: String,
+/// Name of the bot used by the Telegram Login Widget, if Telegram login is enabled
+pub fn telegram_bot_name() -> Option<String> {
+    std::env::var("TG_BOT_SECRET").ok()?;
+    std::env::var("TG_BOT_NAME").ok()
 }
 
-#[inline]
-fn decode_subject(subject: &str) -> Result<String> {
-    let decoded = base64::decode_config(subject, STANDARD_NO_PAD)?;
-    if decoded.len() < 3 {
-        return Err(anyhow!("Subject field is too short"));
+/// Verifies the data sent by the Telegram Login Widget and returns the Telegram user ID.
+/// See https://core.telegram.org/widgets/login#checking-authorization
+fn verify_telegram_auth(secret: &str, fields: &BTreeMap<String, String>) -> Result<String> {
+    let hash = fields.get("hash").ok_or(anyhow!("`hash` is missing"))?;
+    let hash = hex::decode(hash)?;
+    let data = fields
+        .iter()
+        .filter(|(key, _)| key.as_str() != "hash")
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let secret_key = digest::digest(&digest::SHA256, secret.as_bytes());
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret_key.as_ref());
+    hmac::verify(&key, data.as_bytes(), &hash).map_err(|_| anyhow!("Signature mismatch"))?;
+    let auth_date = fields
+        .get("auth_date")
+        .ok_or(anyhow!("`auth_date` is missing"))?
+        .parse::<i64>()?;
+    let now = Utc::now().timestamp();
+    if now - auth_date > TELEGRAM_AUTH_MAX_AGE || auth_date > now + 60 {
+        return Err(anyhow!("Authentication data is outdated"));
     }
-    let len = decoded[1] as usize;
-    if len + 2 > decoded.len() {
-        return Err(anyhow!("Subject field contains invalid length specifier"));
-    }
-    let subject = decoded[2..(len + 2)].to_owned();
 
-    Ok(String::from_utf8(subject)?)
+    Ok(fields
+        .get("id")
+        .ok_or(anyhow!("`id` is missing"))?
+        .to_owned())
 }
 
-async fn validate_jwt_token(token: &str) -> Result<String> {
-    let jwk_url = std::env::var("OAUTH_JWK_URL")?;
-    let header = decode_header(token)?;
-    let kid = header.kid.ok_or(anyhow!("`kid` is missing from header"))?;
-    let mut resp = awcClient::default()
-        .get(jwk_url)
-        .send()
-        .await
-        .map_err(|_| anyhow!("Failed to send JWK request"))?;
-    let jwk = resp.json::<JWK>().await?;
-    for key in jwk.keys {
-        if key.kid == kid {
-            let claims = decode::<DexClaim>(
-                token,
-                &DecodingKey::from_rsa_components(&key.n, &key.e),
-                &Validation::new(Algorithm::RS256),
-            )?;
-            info!("OAuth2 token verified");
-            let username = decode_subject(&claims.claims.sub)?;
-            return Ok(username);
+#[get("/oauth/telegram")]
+pub async fn oauth_telegram(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    session: Session,
+    query: web::Query<BTreeMap<String, String>>,
+    providers: web::Data<OauthProviders>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    let secret = match std::env::var("TG_BOT_SECRET") {
+        Ok(secret) => secret,
+        Err(_) => return Ok(BAD_REQUEST!()),
+    };
+    let telegram_id = match verify_telegram_auth(&secret, &query) {
+        Ok(telegram_id) => telegram_id,
+        Err(err) => {
+            info!("Telegram authentication failed: {}", err);
+            return Ok(NOT_AUTHORIZED!());
         }
-    }
+    };
+    info!("Telegram authentication verified");
 
-    Err(anyhow!("Failed to verify token"))
+    let username = query.get("username").cloned();
+
+    oauth_complete(
+        pool.as_ref(),
+        &id,
+        &session,
+        "Telegram",
+        telegram_id,
+        username,
+        base_url,
+        &providers,
+    )
+    .await
 }
 
-pub async fn oauth_telegram(
+#[post("/oauth/telegram/unlink")]
+pub async fn oauth_telegram_unlink(
     pool: web::Data<PgPool>,
-    req: HttpRequest,
+    id: Identity,
+    form: CsrfForm<OauthRemovalRequest>,
 ) -> Result<HttpResponse, Error> {
-    // TODO: make this a config
-    // if let Ok(secret) = std::env::var("TG_BOT_SECRET") {
+    oauth_unlink(pool.as_ref(), &id, "Telegram", form.oid.clone()).await
+}
 
-    // }
-    Ok(BAD_REQUEST!())
+/// Redirects the user to the authorization page of the provider, with a fresh CSRF token
+/// stored in the session
+fn oauth_authorize(session: &Session, provider: &OauthProvider) -> Result<HttpResponse, Error> {
+    let mut request = provider.client.authorize_url(CsrfToken::new_random);
+    for scope in provider.config.scopes.iter() {
+        request = request.add_scope(Scope::new(scope.to_string()));
+    }
+    let nonce = if provider.is_oidc() {
+        let nonce = CsrfToken::new_random().secret().to_string();
+        request = request.add_extra_param("nonce", nonce.clone());
+        Some(nonce)
+    } else {
+        None
+    };
+    let (auth_url, csrf_token) = request.url();
+    session.set(
+        &provider.slug(),
+        AuthorizationState {
+            csrf_token: csrf_token.secret().to_string(),
+            nonce,
+        },
+    )?;
+
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, auth_url.to_string())
+        .body(format!(
+            "We are sending you to the {} identity service",
+            provider.config.name
+        )))
 }
 
-#[get("/oauth/aosc")]
-pub async fn oauth_aosc(
+/// Handles the redirect back from the provider
+async fn oauth_callback(
     pool: web::Data<PgPool>,
     id: Identity,
     query: web::Query<OauthCallback>,
     session: Session,
-    oauth: web::Data<
-        Client<
-            StandardErrorResponse<BasicErrorResponseType>,
-            StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
-            BasicTokenType,
-            BasicTokenIntrospectionResponse,
-            StandardRevocableToken,
-            BasicRevocationErrorResponse,
-        >,
-    >,
+    providers: web::Data<OauthProviders>,
+    path: web::Path<(String,)>,
+    base_url: String,
 ) -> Result<HttpResponse, Error> {
-    if let Some(id) = id.identity() {
-        if let Some(csrf_token) = session.get::<String>("aosc")? {
-            info!("OAuth2 challenge received");
-            if query.state != csrf_token {
-                info!("OAuth2 challenge failed: CSRF token mismatch.");
-                return Ok(BAD_REQUEST!());
-            }
-            let token = oauth
-                .exchange_code(AuthorizationCode::new(query.code.clone()))
-                .request_async(async_http_client)
-                .await
-                .map_err(|_| {
-                    HttpResponse::Unauthorized()
-                        .header(http::header::CONTENT_TYPE, "text/html")
-                        .body("Upstream identity service does not like us")
-                })?;
-            info!("OAuth2 challenge verified by idp");
-            let name = validate_jwt_token(token.access_token().secret())
-                .await
-                .map_err(|_| BAD_REQUEST!())?;
-            let user = db::get_user_by_username(pool.as_ref(), &id)
-                .await
-                .map_err(|_| INTERNAL_ERROR!())?;
-            db::add_oauth_info(
-                pool.as_ref(),
-                Oauth {
-                    uid: user.id,
-                    type_: "AOSC".to_string(),
-                    oid: Some(name),
-                    token: None,
-                },
-            )
-            .await
-            .map_err(|_| INTERNAL_ERROR!())?;
-            info!("OAuth2 account added: {}", id);
-            return Ok(HttpResponse::Found()
-                .header(http::header::LOCATION, "/account")
-                .body("Authentication successful. Let's heading home..."));
-        }
+    let provider = match providers.get(&(path.0).0) {
+        Some(provider) => provider,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let state = match session.get::<AuthorizationState>(&provider.slug())? {
+        Some(state) => state,
+        None => return Ok(BAD_REQUEST!()),
+    };
+    session.remove(&provider.slug());
+    info!("OAuth2 challenge received");
+    if query.state != state.csrf_token {
+        info!("OAuth2 challenge failed: CSRF token mismatch.");
+        return Ok(BAD_REQUEST!());
     }
+    let token = provider
+        .client
+        .exchange_code(AuthorizationCode::new(query.code.clone()))
+        .request_async(async_http_client)
+        .await
+        .map_err(|_| {
+            HttpResponse::Unauthorized()
+                .header(http::header::CONTENT_TYPE, "text/html")
+                .body("Upstream identity service does not like us")
+        })?;
+    info!("OAuth2 challenge verified by idp");
+    let identity = provider
+        .identify(&token, state.nonce.as_deref())
+        .await
+        .map_err(|err| {
+            info!("OAuth2 token rejected: {}", err);
+            BAD_REQUEST!()
+        })?;
+
+    oauth_complete(
+        pool.as_ref(),
+        &id,
+        &session,
+        &provider.config.name,
+        identity.subject,
+        identity.username,
+        base_url,
+        &providers,
+    )
+    .await
+}
+
+// The AOSC SSO was registered with the redirect URL without `/callback`
+#[get("/oauth/{provider}")]
+pub async fn oauth_provider(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    query: web::Query<OauthCallback>,
+    session: Session,
+    providers: web::Data<OauthProviders>,
+    path: web::Path<(String,)>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    oauth_callback(pool, id, query, session, providers, path, base_url).await
+}
 
-    Ok(BAD_REQUEST!())
+#[get("/oauth/{provider}/callback")]
+pub async fn oauth_provider_callback(
+    pool: web::Data<PgPool>,
+    id: Identity,
+    query: web::Query<OauthCallback>,
+    session: Session,
+    providers: web::Data<OauthProviders>,
+    path: web::Path<(String,)>,
+    base_url: String,
+) -> Result<HttpResponse, Error> {
+    oauth_callback(pool, id, query, session, providers, path, base_url).await
 }
 
-#[get("/oauth/aosc/new")]
-pub async fn oauth_aosc_new(
+#[get("/oauth/{provider}/new")]
+pub async fn oauth_provider_new(
     id: Identity,
     session: Session,
-    oauth: web::Data<
-        Client<
-            StandardErrorResponse<BasicErrorResponseType>,
-            StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
-            BasicTokenType,
-            BasicTokenIntrospectionResponse,
-            StandardRevocableToken,
-            BasicRevocationErrorResponse,
-        >,
-    >,
+    providers: web::Data<OauthProviders>,
+    path: web::Path<(String,)>,
 ) -> Result<HttpResponse, Error> {
     if id.identity().is_none() {
         return Ok(BAD_REQUEST!());
     }
-    let (auth_url, csrf_token) = oauth
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("profile".to_string()))
-        .add_scope(Scope::new("openid".to_string()))
-        .url();
-    session.set("aosc", csrf_token.secret())?;
 
-    Ok(HttpResponse::Found()
-        .header(http::header::LOCATION, auth_url.to_string())
-        .body("We are sending you to the AOSC identity service"))
+    match providers.get(&(path.0).0) {
+        Some(provider) => oauth_authorize(&session, provider),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[get("/oauth/{provider}/login")]
+pub async fn oauth_provider_login(
+    id: Identity,
+    session: Session,
+    providers: web::Data<OauthProviders>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    if id.identity().is_some() {
+        return Ok(HttpResponse::Found()
+            .header(http::header::LOCATION, "/account")
+            .finish());
+    }
+
+    match providers.get(&(path.0).0) {
+        Some(provider) => oauth_authorize(&session, provider),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
 }
 
-#[get("/oauth/aosc/unlink")]
-pub async fn oauth_aosc_unlink(
+#[post("/oauth/{provider}/unlink")]
+pub async fn oauth_provider_unlink(
     pool: web::Data<PgPool>,
     id: Identity,
-    query: web::Query<OauthRemovalRequest>,
+    form: CsrfForm<OauthRemovalRequest>,
+    providers: web::Data<OauthProviders>,
+    path: web::Path<(String,)>,
 ) -> Result<HttpResponse, Error> {
-    if id.identity().is_none() {
-        return Ok(BAD_REQUEST!());
+    let provider = match providers.get(&(path.0).0) {
+        Some(provider) => provider,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    oauth_unlink(pool.as_ref(), &id, &provider.config.name, form.oid.clone()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "123456:telegram-bot-token";
+
+    /// Fields signed the way the Telegram Login Widget does
+    fn signed_fields(auth_date: i64) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        fields.insert("id".to_string(), "42".to_string());
+        fields.insert("first_name".to_string(), "Tester".to_string());
+        fields.insert("username".to_string(), "tester".to_string());
+        fields.insert("auth_date".to_string(), auth_date.to_string());
+        let data = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let secret_key = digest::digest(&digest::SHA256, SECRET.as_bytes());
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret_key.as_ref());
+        let hash = hex::encode(hmac::sign(&key, data.as_bytes()));
+        fields.insert("hash".to_string(), hash);
+
+        fields
     }
-    let user = db::get_user_by_username(pool.as_ref(), &id.identity().unwrap())
-        .await
-        .map_err(|_| BAD_REQUEST!())?;
-    db::delete_oauth_info(
-        pool.as_ref(),
-        Oauth {
-            uid: user.id,
-            type_: "AOSC".to_string(),
-            oid: Some(query.oid.clone()),
-            token: None,
-        },
-    )
-    .await
-    .map_err(|_| INTERNAL_ERROR!())?;
 
-    return Ok(HttpResponse::Found()
-        .header(http::header::LOCATION, "/account")
-        .finish());
+    #[test]
+    fn valid_hash_is_accepted() {
+        let fields = signed_fields(Utc::now().timestamp());
+        assert_eq!(verify_telegram_auth(SECRET, &fields).unwrap(), "42");
+    }
+
+    #[test]
+    fn tampered_field_is_rejected() {
+        let mut fields = signed_fields(Utc::now().timestamp());
+        fields.insert("id".to_string(), "43".to_string());
+        assert!(verify_telegram_auth(SECRET, &fields).is_err());
+    }
+
+    #[test]
+    fn stale_auth_date_is_rejected() {
+        let fields = signed_fields(Utc::now().timestamp() - TELEGRAM_AUTH_MAX_AGE - 1);
+        assert!(verify_telegram_auth(SECRET, &fields).is_err());
+    }
+
+    #[test]
+    fn missing_hash_is_rejected() {
+        let mut fields = signed_fields(Utc::now().timestamp());
+        fields.remove("hash");
+        assert!(verify_telegram_auth(SECRET, &fields).is_err());
+    }
 }
@@ -0,0 +1,158 @@
+use crate::db;
+use crate::models::{IterEntry, IterEntryNode, IterPlan};
+use actix_web::{get, http, web, Error, HttpResponse};
+use sqlx::PgPool;
+use yarte::Template;
+
+const CLOSE_SUBTREE: &str = "</ul></details></li>";
+
+#[derive(Template)]
+#[template(path = "plans.hbs")]
+struct PlansTemplate {
+    base_url: String,
+    plans: Vec<IterPlan>,
+    banner_subtitle: String,
+}
+
+#[derive(Template)]
+#[template(path = "plan.hbs")]
+struct PlanTemplate {
+    base_url: String,
+    plan: IterPlan,
+    categories: Vec<EntryCategory>,
+    completion: usize,
+    title: String,
+    banner_title: String,
+    banner_subtitle: String,
+}
+
+struct EntryCategory {
+    name: String,
+    done: usize,
+    total: usize,
+    rows: Vec<EntryRow>,
+}
+
+/// An entry in a flattened tree. Entries with children open a nested list,
+/// which is closed by the markup in `closing` of the last row of the subtree.
+struct EntryRow {
+    entry: IterEntry,
+    has_children: bool,
+    closing: String,
+}
+
+fn flatten_entries(nodes: Vec<IterEntryNode>, rows: &mut Vec<EntryRow>) -> (usize, usize) {
+    let mut done = 0;
+    let mut total = 0;
+    for node in nodes {
+        total += 1;
+        if node.entry.done {
+            done += 1;
+        }
+        let has_children = !node.children.is_empty();
+        rows.push(EntryRow {
+            entry: node.entry,
+            has_children,
+            closing: String::new(),
+        });
+        if has_children {
+            let (children_done, children_total) = flatten_entries(node.children, rows);
+            done += children_done;
+            total += children_total;
+            if let Some(last) = rows.last_mut() {
+                last.closing.push_str(CLOSE_SUBTREE);
+            }
+        }
+    }
+
+    (done, total)
+}
+
+/// Groups the top-level entries by category, keeping children under their parents
+fn group_entries(tree: Vec<IterEntryNode>) -> Vec<EntryCategory> {
+    let mut categories: Vec<EntryCategory> = Vec::new();
+    for node in tree {
+        let position = categories
+            .iter()
+            .position(|category| category.name == node.entry.category);
+        let category = match position {
+            Some(position) => &mut categories[position],
+            None => {
+                categories.push(EntryCategory {
+                    name: node.entry.category.clone(),
+                    done: 0,
+                    total: 0,
+                    rows: Vec::new(),
+                });
+                categories.last_mut().unwrap()
+            }
+        };
+        let (done, total) = flatten_entries(vec![node], &mut category.rows);
+        category.done += done;
+        category.total += total;
+    }
+
+    categories
+}
+
+#[get("/plans")]
+pub async fn plans(pool: web::Data<PgPool>, base_url: String) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let plans = db::get_plans(&conn)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let response = PlansTemplate {
+        base_url,
+        banner_subtitle: format!("{} iteration plans in total", plans.len()),
+        plans,
+    };
+    let res = HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            response
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        );
+    Ok(res)
+}
+
+#[get("/plan/{id}")]
+pub async fn plan(
+    pool: web::Data<PgPool>,
+    base_url: String,
+    path: web::Path<(i64,)>,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let plan_id = (path.0).0;
+    let plan = db::get_plan_by_id(&conn, plan_id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let plan = match plan {
+        Some(plan) => plan,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let tree = db::get_plan_entry_tree(&conn, plan_id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let categories = group_entries(tree);
+    let done: usize = categories.iter().map(|category| category.done).sum();
+    let total: usize = categories.iter().map(|category| category.total).sum();
+    let completion = if total > 0 { done * 100 / total } else { 0 };
+    let response = PlanTemplate {
+        base_url,
+        title: format!("{} - AOSC OS Iteration Plans", plan.title),
+        banner_title: plan.title.clone(),
+        banner_subtitle: format!("{}% completed ({} of {} entries)", completion, done, total),
+        plan,
+        categories,
+        completion,
+    };
+    let res = HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/html")
+        .body(
+            response
+                .call()
+                .unwrap_or("Internal Server Error".to_string()),
+        );
+    Ok(res)
+}
@@ -0,0 +1,489 @@
+//! OAuth2 identity providers, configured from the environment or a JSON file
+use crate::jwks::JwksCache;
+use anyhow::{anyhow, Result};
+use base64::STANDARD_NO_PAD;
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use log::{info, warn};
+use oauth2::basic::{
+    BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+    BasicTokenType,
+};
+use oauth2::{reqwest::async_http_client, url::Url, HttpRequest};
+use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
+use oauth2::{
+    Client, ExtraTokenFields, StandardRevocableToken, StandardTokenResponse, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Name of the AOSC SSO, whose users are trusted to register without approval
+pub const AOSC_PROVIDER: &str = "AOSC";
+
+/// The `id_token` returned alongside the access token by OpenID Connect providers
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OidcTokenFields {
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for OidcTokenFields {}
+
+pub type OidcTokenResponse = StandardTokenResponse<OidcTokenFields, BasicTokenType>;
+
+pub type OidcClient = Client<
+    BasicErrorResponse,
+    OidcTokenResponse,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+/// A user as identified by a provider
+pub struct ExternalIdentity {
+    /// Stable user ID, stored as `oauth.oid`
+    pub subject: String,
+    /// Suggested username for new accounts
+    pub username: Option<String>,
+}
+
+/// How the subject claim is encoded
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectFormat {
+    Plain,
+    /// Dex wraps the user ID of the upstream connector in a base64-encoded protobuf message
+    Dex,
+}
+
+impl Default for SubjectFormat {
+    fn default() -> Self {
+        SubjectFormat::Plain
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderConfig {
+    /// Stored as `oauth.type`; its lowercase form is used in the URLs
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,
+    pub token_url: String,
+    /// Defaults to `{BASE_URL}/oauth/{provider}/callback`
+    pub redirect_url: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Set when the tokens are JWTs, which are then validated with these keys.
+    /// Required for OpenID Connect providers, which request the `openid` scope.
+    pub jwks_url: Option<String>,
+    /// Expected `iss` of the JWTs; required for OpenID Connect providers
+    pub issuer: Option<String>,
+    /// Otherwise the claims are read from this endpoint
+    pub userinfo_url: Option<String>,
+    /// The claim holding the stable user ID
+    #[serde(default = "default_subject_claim")]
+    pub subject_claim: String,
+    #[serde(default)]
+    pub subject_format: SubjectFormat,
+}
+
+fn default_subject_claim() -> String {
+    "sub".to_string()
+}
+
+#[derive(Clone)]
+pub struct OauthProvider {
+    pub config: ProviderConfig,
+    pub client: OidcClient,
+    jwks: Option<Arc<JwksCache>>,
+}
+
+/// Name of a provider as shown on the pages
+pub struct ProviderLink {
+    pub name: String,
+    pub slug: String,
+}
+
+#[derive(Clone, Default)]
+pub struct OauthProviders(Vec<OauthProvider>);
+
+impl OauthProvider {
+    pub fn new(config: ProviderConfig, base_url: &str) -> Result<Self> {
+        let redirect_url = config.redirect_url.clone().unwrap_or(format!(
+            "{}/oauth/{}/callback",
+            base_url,
+            config.name.to_ascii_lowercase()
+        ));
+        if config.jwks_url.is_none() && config.userinfo_url.is_none() {
+            return Err(anyhow!(
+                "Provider {} needs either `jwks_url` or `userinfo_url`",
+                config.name
+            ));
+        }
+        if config.scopes.iter().any(|scope| scope == "openid")
+            && (config.jwks_url.is_none() || config.issuer.is_none())
+        {
+            return Err(anyhow!(
+                "OpenID Connect provider {} needs both `jwks_url` and `issuer`",
+                config.name
+            ));
+        }
+        let client = OidcClient::new(
+            ClientId::new(config.client_id.clone()),
+            Some(ClientSecret::new(config.client_secret.clone())),
+            AuthUrl::new(config.auth_url.clone())?,
+            Some(TokenUrl::new(config.token_url.clone())?),
+        )
+        .set_redirect_uri(RedirectUrl::new(redirect_url)?);
+        if config.jwks_url.is_some() && config.issuer.is_none() {
+            warn!(
+                "Issuer of {} is not configured and will not be checked",
+                config.name
+            );
+        }
+        let jwks = config
+            .jwks_url
+            .clone()
+            .map(|url| Arc::new(JwksCache::new(url)));
+
+        Ok(OauthProvider {
+            config,
+            client,
+            jwks,
+        })
+    }
+
+    pub fn slug(&self) -> String {
+        self.config.name.to_ascii_lowercase()
+    }
+
+    /// Whether the provider returns an `id_token` to identify the user
+    pub fn is_oidc(&self) -> bool {
+        self.config.scopes.iter().any(|scope| scope == "openid")
+    }
+
+    /// Identifies the user the tokens were issued to. OpenID Connect providers are checked
+    /// through the `id_token`, which must carry the `nonce` sent with the authorization request.
+    pub async fn identify(
+        &self,
+        token: &OidcTokenResponse,
+        nonce: Option<&str>,
+    ) -> Result<ExternalIdentity> {
+        let access_token = token.access_token().secret();
+        let claims = match (self.is_oidc(), self.jwks.as_ref()) {
+            (true, Some(jwks)) => {
+                let id_token = token
+                    .extra_fields()
+                    .id_token
+                    .as_ref()
+                    .ok_or(anyhow!("`id_token` is missing from the token response"))?;
+                let claims = validate_jwt_token(jwks, &self.config, id_token).await?;
+                match (claims.get("nonce"), nonce) {
+                    (Some(Value::String(claimed)), Some(nonce)) if claimed == nonce => (),
+                    _ => return Err(anyhow!("Nonce mismatch")),
+                }
+                claims
+            }
+            (false, Some(jwks)) => validate_jwt_token(jwks, &self.config, access_token).await?,
+            (_, None) => match self.config.userinfo_url.as_ref() {
+                Some(userinfo_url) => fetch_userinfo(userinfo_url, access_token).await?,
+                None => return Err(anyhow!("No way to verify the token")),
+            },
+        };
+
+        Ok(ExternalIdentity {
+            subject: self.subject(&claims)?,
+            username: string_claim(&claims, "preferred_username").or_else(|| {
+                string_claim(&claims, "email")
+                    .and_then(|email| email.split('@').next().map(str::to_string))
+            }),
+        })
+    }
+
+    fn subject(&self, claims: &Map<String, Value>) -> Result<String> {
+        let subject = match claims.get(&self.config.subject_claim) {
+            Some(Value::String(subject)) => subject.clone(),
+            Some(Value::Number(subject)) => subject.to_string(),
+            _ => {
+                return Err(anyhow!(
+                    "`{}` is missing from claims",
+                    self.config.subject_claim
+                ))
+            }
+        };
+
+        match self.config.subject_format {
+            SubjectFormat::Plain => Ok(subject),
+            SubjectFormat::Dex => decode_subject(&subject),
+        }
+    }
+}
+
+#[inline]
+fn string_claim(claims: &Map<String, Value>, name: &str) -> Option<String> {
+    claims.get(name)?.as_str().map(str::to_string)
+}
+
+impl OauthProviders {
+    /// Reads the providers from the JSON file at `OAUTH_PROVIDERS_FILE`.
+    /// Without it, the AOSC SSO is configured from `OAUTH_*` and GitHub from `GITHUB_*`.
+    pub fn from_env(base_url: &str) -> Result<Self> {
+        let configs = match std::env::var("OAUTH_PROVIDERS_FILE") {
+            Ok(path) => serde_json::from_slice::<Vec<ProviderConfig>>(&std::fs::read(path)?)?,
+            Err(_) => legacy_configs(base_url, |name| std::env::var(name).ok())?,
+        };
+        let mut providers = OauthProviders::default();
+        for config in configs {
+            if providers.get(&config.name.to_ascii_lowercase()).is_some() {
+                return Err(anyhow!("Provider {} is configured twice", config.name));
+            }
+            providers.0.push(OauthProvider::new(config, base_url)?);
+        }
+
+        Ok(providers)
+    }
+
+    /// Finds a provider by the lowercase name used in the URLs
+    pub fn get(&self, slug: &str) -> Option<&OauthProvider> {
+        self.0.iter().find(|provider| provider.slug() == slug)
+    }
+
+    pub fn links(&self) -> Vec<ProviderLink> {
+        self.0
+            .iter()
+            .map(|provider| ProviderLink {
+                name: provider.config.name.clone(),
+                slug: provider.slug(),
+            })
+            .collect()
+    }
+}
+
+/// Reads the configuration through `var`, which looks up environment variables
+fn legacy_configs<F>(base_url: &str, var: F) -> Result<Vec<ProviderConfig>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut configs = vec![ProviderConfig {
+        name: AOSC_PROVIDER.to_string(),
+        client_id: var("OAUTH_CLIENT_ID").ok_or_else(|| anyhow!("OAUTH_CLIENT_ID not set"))?,
+        client_secret: var("OAUTH_SECRET").ok_or_else(|| anyhow!("OAUTH_SECRET not set"))?,
+        auth_url: var("OAUTH_AUTH_URL").ok_or_else(|| anyhow!("OAUTH_AUTH_URL not set"))?,
+        token_url: var("OAUTH_TOKEN_URL").ok_or_else(|| anyhow!("OAUTH_TOKEN_URL not set"))?,
+        // Already registered at the identity service
+        redirect_url: Some(format!("{}/oauth/aosc/", base_url)),
+        scopes: vec![
+            "openid".to_string(),
+            "profile".to_string(),
+            "email".to_string(),
+        ],
+        jwks_url: Some(var("OAUTH_JWK_URL").ok_or_else(|| anyhow!("OAUTH_JWK_URL not set"))?),
+        issuer: Some(var("OAUTH_ISSUER").ok_or_else(|| anyhow!("OAUTH_ISSUER not set"))?),
+        userinfo_url: None,
+        // Linked accounts hold the user ID decoded from `sub`, which cannot be
+        // taken over by renaming, unlike `preferred_username`
+        subject_claim: default_subject_claim(),
+        subject_format: SubjectFormat::Dex,
+    }];
+    if let Some(client_id) = var("GITHUB_CLIENT_ID") {
+        // The endpoints can be overridden to test against a mock server
+        let api_url = var("GITHUB_API_URL").unwrap_or("https://api.github.com".to_string());
+        configs.push(ProviderConfig {
+            name: "GitHub".to_string(),
+            client_id,
+            client_secret: var("GITHUB_SECRET").ok_or_else(|| anyhow!("GITHUB_SECRET not set"))?,
+            auth_url: var("GITHUB_AUTH_URL")
+                .unwrap_or("https://github.com/login/oauth/authorize".to_string()),
+            token_url: var("GITHUB_TOKEN_URL")
+                .unwrap_or("https://github.com/login/oauth/access_token".to_string()),
+            redirect_url: Some(format!("{}/oauth/github", base_url)),
+            scopes: vec!["read:user".to_string()],
+            jwks_url: None,
+            issuer: None,
+            userinfo_url: Some(format!("{}/user", api_url.trim_end_matches('/'))),
+            // User IDs stay the same when users rename themselves, unlike their logins
+            subject_claim: "id".to_string(),
+            subject_format: SubjectFormat::Plain,
+        });
+    }
+
+    Ok(configs)
+}
+
+#[inline]
+fn decode_subject(subject: &str) -> Result<String> {
+    let decoded = base64::decode_config(subject, STANDARD_NO_PAD)?;
+    if decoded.len() < 3 {
+        return Err(anyhow!("Subject field is too short"));
+    }
+    let len = decoded[1] as usize;
+    if len + 2 > decoded.len() {
+        return Err(anyhow!("Subject field contains invalid length specifier"));
+    }
+    let subject = decoded[2..(len + 2)].to_owned();
+
+    Ok(String::from_utf8(subject)?)
+}
+
+async fn validate_jwt_token(
+    jwks: &JwksCache,
+    config: &ProviderConfig,
+    token: &str,
+) -> Result<Map<String, Value>> {
+    let header = decode_header(token)?;
+    let kid = header.kid.ok_or(anyhow!("`kid` is missing from header"))?;
+    let key = jwks.key(&kid).await?;
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.iss = config.issuer.clone();
+    validation.set_audience(&[&config.client_id]);
+    let claims = decode::<Map<String, Value>>(token, &key, &validation)?;
+    info!("OAuth2 token verified");
+
+    Ok(claims.claims)
+}
+
+async fn fetch_userinfo(userinfo_url: &str, token: &str) -> Result<Map<String, Value>> {
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+        http::header::AUTHORIZATION,
+        http::HeaderValue::from_str(&format!("Bearer {}", token))?,
+    );
+    headers.insert(
+        http::header::ACCEPT,
+        http::HeaderValue::from_static("application/json"),
+    );
+    // Required by the GitHub API
+    headers.insert(
+        http::header::USER_AGENT,
+        http::HeaderValue::from_static("pakreqWeb-rs"),
+    );
+    let resp = async_http_client(HttpRequest {
+        url: Url::parse(userinfo_url)?,
+        method: http::Method::GET,
+        headers,
+        body: Vec::new(),
+    })
+    .await?;
+    if !resp.status_code.is_success() {
+        return Err(anyhow!("User info endpoint returned {}", resp.status_code));
+    }
+    let claims = serde_json::from_slice::<Map<String, Value>>(&resp.body)?;
+    info!("OAuth2 user info fetched");
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oauth2::AuthorizationCode;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    /// Reads the request line, the headers and the body of a request
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+
+        String::from_utf8_lossy(&request).into_owned()
+    }
+
+    /// Serves the token and user endpoints of GitHub, returning the base URL of the server
+    fn mock_github() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = read_request(&mut stream);
+                let (status, body) = if request.starts_with("POST /login/oauth/access_token")
+                    && request.contains("code=test-code")
+                {
+                    (
+                        "200 OK",
+                        r#"{"access_token":"gho_test","token_type":"bearer","scope":"read:user"}"#,
+                    )
+                } else if request.starts_with("GET /user") && request.contains("Bearer gho_test") {
+                    ("200 OK", r#"{"id":123,"login":"octocat"}"#)
+                } else {
+                    ("404 Not Found", r#"{"message":"Not Found"}"#)
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn github_identifies_users_by_id() {
+        let url = mock_github();
+        let vars = [
+            ("OAUTH_CLIENT_ID", "pakreq".to_string()),
+            ("OAUTH_SECRET", "secret".to_string()),
+            ("OAUTH_AUTH_URL", "https://sso.example.org/auth".to_string()),
+            (
+                "OAUTH_TOKEN_URL",
+                "https://sso.example.org/token".to_string(),
+            ),
+            ("OAUTH_JWK_URL", "https://sso.example.org/keys".to_string()),
+            ("OAUTH_ISSUER", "https://sso.example.org".to_string()),
+            ("GITHUB_CLIENT_ID", "github-client".to_string()),
+            ("GITHUB_SECRET", "github-secret".to_string()),
+            ("GITHUB_AUTH_URL", format!("{}/login/oauth/authorize", url)),
+            (
+                "GITHUB_TOKEN_URL",
+                format!("{}/login/oauth/access_token", url),
+            ),
+            ("GITHUB_API_URL", url.clone()),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<_, _>>();
+        let configs =
+            legacy_configs("http://localhost:8080", |name| vars.get(name).cloned()).unwrap();
+        let github = configs
+            .into_iter()
+            .find(|config| config.name == "GitHub")
+            .map(|config| OauthProvider::new(config, "http://localhost:8080").unwrap())
+            .unwrap();
+
+        let token = github
+            .client
+            .exchange_code(AuthorizationCode::new("test-code".to_string()))
+            .request_async(async_http_client)
+            .await
+            .unwrap();
+        assert_eq!(token.access_token().secret(), "gho_test");
+        let identity = github.identify(&token, None).await.unwrap();
+        assert_eq!(identity.subject, "123");
+    }
+}
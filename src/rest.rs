@@ -1,29 +1,91 @@
+use crate::actions::{self, ActionError};
+use crate::models::{
+    CommentInput, IterEntryInput, IterPlanInput, RegisterInput, Request, RequestFilter,
+    RequestInput, RequestPatch, SearchQuery, User, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE,
+};
+use crate::signing::JwtKeys;
+use crate::tokens::{
+    random_token, token_hash, ALL_SCOPES, SCOPE_ADMIN, SCOPE_WRITE_REQUESTS, TOKEN_PREFIX,
+};
 use crate::{auth, db};
-use actix_web::{web, Error};
-use actix_web::{http, HttpRequest, HttpResponse};
+use actix_web::{dev::Payload, web, Error, FromRequest};
+use actix_web::{http, http::Method, HttpRequest, HttpResponse};
 use chrono::{DateTime, Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
-use std::path::{Iter, PathBuf};
 use sqlx::PgPool;
+use std::future::Future;
+use std::path::{Iter, PathBuf};
+use std::pin::Pin;
 
 pub const BAD_REQUEST_RETURN: &'static str = r#"{"success": false, "message": "Bad Request"}"#;
-pub const INTERNAL_ERR_RESPONSE: &'static str = r#"{"success": false, "message": "Internal error"}"#;
-pub const NOT_AUTHORIZED_RESPONSE: &'static str = r#"{"success": false, "message": "Not authorized"}"#;
+pub const INTERNAL_ERR_RESPONSE: &'static str =
+    r#"{"success": false, "message": "Internal error"}"#;
+pub const NOT_AUTHORIZED_RESPONSE: &'static str =
+    r#"{"success": false, "message": "Not authorized"}"#;
+
+/// Lifetime of the JWTs returned by `/api/login` and `/api/token/refresh`
+const ACCESS_TOKEN_DAYS: i64 = 1;
+/// Lifetime of the refresh tokens, each of which can be used once
+const REFRESH_TOKEN_DAYS: i64 = 30;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct UserClaims {
     sub: String,
+    /// Identifies the token in the denylist
+    jti: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
     nbf: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
     exp: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
     iat: DateTime<Utc>,
 }
 
+/// Extracts the caller from an `Authorization: Bearer <token>` header, holding either a JWT
+/// from `/api/login` or a personal access token, along with the scopes the token grants.
+/// Use `Option<AuthenticatedUser>` for endpoints that also serve anonymous callers.
+pub struct AuthenticatedUser(pub User, pub Vec<String>);
+
+impl AuthenticatedUser {
+    /// Without the admin scope, admins are treated like everyone else,
+    /// so that the admin overrides of the actions do not apply either
+    pub fn new(mut user: User, scopes: Vec<String>) -> Self {
+        if !scopes.iter().any(|scope| scope == SCOPE_ADMIN) {
+            user.admin = false;
+        }
+
+        AuthenticatedUser(user, scopes)
+    }
+
+    pub fn allows(&self, scope: &str) -> bool {
+        self.1.iter().any(|granted| granted == scope)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AssignInput {
+    packager: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestsResponse {
+    success: bool,
+    total: i64,
+    next_cursor: Option<i64>,
+    requests: Vec<Request>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct TokenResponse {
     success: bool,
     token: String,
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshInput {
+    refresh_token: String,
 }
 
 #[macro_export]
@@ -53,6 +115,15 @@ macro_rules! NOT_AUTHORIZED {
     };
 }
 
+#[macro_export]
+macro_rules! ERROR_MESSAGE {
+    ($s:ident, $m:expr) => {
+        HttpResponse::$s()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::json!({"success": false, "message": $m}).to_string())
+    };
+}
+
 #[macro_export]
 macro_rules! OK {
     ($r:ident) => {
@@ -62,9 +133,50 @@ macro_rules! OK {
     };
 }
 
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        let keys = req.app_data::<web::Data<JwtKeys>>().cloned();
+        let token = bearer_token(req);
+        Box::pin(async move {
+            let (pool, keys, token) = match (pool, keys, token) {
+                (Some(pool), Some(keys), Some(token)) => (pool, keys, token),
+                _ => return Err(Error::from(NOT_AUTHORIZED!())),
+            };
+            let (username, scopes) = if token.starts_with(TOKEN_PREFIX) {
+                db::use_api_token(pool.get_ref(), &token_hash(&token))
+                    .await
+                    .map_err(|_| INTERNAL_ERROR!())?
+                    .ok_or_else(|| NOT_AUTHORIZED!())?
+            } else {
+                let claims = validate_jwt_token(pool.get_ref(), keys, token)
+                    .await
+                    .map_err(|_| NOT_AUTHORIZED!())?;
+                let scopes = ALL_SCOPES.iter().map(|scope| scope.to_string()).collect();
+                (claims.sub, scopes)
+            };
+            let user = db::get_user_by_username(pool.get_ref(), &username)
+                .await
+                .map_err(|_| NOT_AUTHORIZED!())?;
+            if user.locked || !user.approved {
+                return Err(Error::from(NOT_AUTHORIZED!()));
+            }
+
+            Ok(AuthenticatedUser::new(user, scopes))
+        })
+    }
+}
+
 pub async fn rest_dispatch(
     pool: web::Data<PgPool>,
+    keys: web::Data<JwtKeys>,
     req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    body: web::Bytes,
 ) -> Result<HttpResponse, Error> {
     let path = req.match_info().query("endpoint").parse::<PathBuf>();
     if path.is_err() {
@@ -74,10 +186,27 @@ pub async fn rest_dispatch(
     let mut components = path.iter();
     if let Some(component) = components.next() {
         return {
-            match &*component.to_string_lossy() {
-                "requests" => rest_requests(pool, components).await,
-                "request" => rest_request_detail(pool, components).await,
-                "login" => rest_login(pool, &req).await,
+            match (req.method(), &*component.to_string_lossy()) {
+                (&Method::GET, "requests") => rest_requests(pool, &req).await,
+                (&Method::POST, "requests") => rest_new_request(pool, user, body).await,
+                (&Method::GET, "search") => rest_search(pool, &req).await,
+                (&Method::GET, "request") => rest_request_detail(pool, components).await,
+                (&Method::POST, "request") => {
+                    rest_request_action(pool, user, components, body).await
+                }
+                (&Method::PATCH, "request") | (&Method::DELETE, "request") => {
+                    rest_request_modify(pool, req.method(), user, components, body).await
+                }
+                (_, "plans") => rest_plans(pool, req.method(), user, components, body).await,
+                (&Method::GET, "login") => rest_login(pool, keys, &req).await,
+                (&Method::POST, "logout") => rest_logout(pool, keys, &req).await,
+                (&Method::POST, "token") => match components.next() {
+                    Some(refresh) if refresh == "refresh" => {
+                        rest_token_refresh(pool, keys, body).await
+                    }
+                    _ => Ok(BAD_REQUEST!()),
+                },
+                (&Method::POST, "register") => rest_register(pool, body).await,
                 _ => Ok(BAD_REQUEST!()),
             }
         };
@@ -97,10 +226,27 @@ async fn rest_request_detail(
     if let Some(request_id) = request_id {
         let request_id =
             str::parse::<i64>(&request_id.to_string_lossy()).map_err(|_| INTERNAL_ERROR!())?;
-        let detail = db::get_request_detail_by_id(&conn, request_id)
-            .await
-            .map_err(|_| INTERNAL_ERROR!())?;
-        let result = to_string(&detail).map_err(|_| INTERNAL_ERROR!())?;
+        let result = match components.next() {
+            None => {
+                let detail = db::get_request_detail_by_id(&conn, request_id)
+                    .await
+                    .map_err(|_| INTERNAL_ERROR!())?;
+                to_string(&detail).map_err(|_| INTERNAL_ERROR!())?
+            }
+            Some(comments) if comments == "comments" => {
+                let comments = db::get_comments(&conn, request_id)
+                    .await
+                    .map_err(|_| INTERNAL_ERROR!())?;
+                to_string(&comments).map_err(|_| INTERNAL_ERROR!())?
+            }
+            Some(history) if history == "history" => {
+                let history = db::get_request_history(&conn, request_id)
+                    .await
+                    .map_err(|_| INTERNAL_ERROR!())?;
+                to_string(&history).map_err(|_| INTERNAL_ERROR!())?
+            }
+            _ => return Ok(BAD_REQUEST!()),
+        };
         return Ok(OK!(result));
     }
 
@@ -108,20 +254,442 @@ async fn rest_request_detail(
 }
 
 #[inline]
-async fn rest_requests(
+async fn rest_request_action(
+    pool: web::Data<PgPool>,
+    user: Option<AuthenticatedUser>,
+    mut components: Iter<'_>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let user = match user {
+        Some(user) if user.allows(SCOPE_WRITE_REQUESTS) => user.0,
+        Some(_) => return Ok(missing_scope(SCOPE_WRITE_REQUESTS)),
+        None => return Ok(NOT_AUTHORIZED!()),
+    };
+    let request_id = match components.next() {
+        Some(request_id) => {
+            str::parse::<i64>(&request_id.to_string_lossy()).map_err(|_| BAD_REQUEST!())?
+        }
+        None => return Ok(BAD_REQUEST!()),
+    };
+    let action = components.next().map(|action| action.to_string_lossy());
+    let result = match action.as_deref() {
+        Some("claim") => actions::claim_request(&conn, &user, request_id).await,
+        Some("unclaim") => actions::unclaim_request(&conn, &user, request_id).await,
+        Some("assign") => {
+            let input = serde_json::from_slice::<AssignInput>(&body).map_err(|_| BAD_REQUEST!())?;
+            actions::assign_request(&conn, &user, request_id, &input.packager).await
+        }
+        Some("comments") => {
+            let input =
+                serde_json::from_slice::<CommentInput>(&body).map_err(|_| BAD_REQUEST!())?;
+            let comment_id = match actions::add_comment(&conn, &user, request_id, &input).await {
+                Ok(comment_id) => comment_id,
+                Err(err) => return Ok(action_error(err)),
+            };
+            return rest_comment(&conn, request_id, comment_id).await;
+        }
+        Some("close") => actions::close_request(&conn, &user, request_id, false).await,
+        Some("reject") => actions::close_request(&conn, &user, request_id, true).await,
+        _ => return Ok(BAD_REQUEST!()),
+    };
+    if let Err(err) = result {
+        return Ok(action_error(err));
+    }
+    let detail = db::get_request_detail_by_id(&conn, request_id)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let result = to_string(&detail).map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(OK!(result))
+}
+
+#[inline]
+async fn rest_request_modify(
+    pool: web::Data<PgPool>,
+    method: &Method,
+    user: Option<AuthenticatedUser>,
+    mut components: Iter<'_>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let user = match user {
+        Some(user) if user.allows(SCOPE_WRITE_REQUESTS) => user.0,
+        Some(_) => return Ok(missing_scope(SCOPE_WRITE_REQUESTS)),
+        None => return Ok(NOT_AUTHORIZED!()),
+    };
+    let request_id = match parse_id(components.next()) {
+        Some(request_id) => request_id,
+        None => return Ok(BAD_REQUEST!()),
+    };
+    match components.next() {
+        None if method == Method::PATCH => {
+            let patch =
+                serde_json::from_slice::<RequestPatch>(&body).map_err(|_| BAD_REQUEST!())?;
+            if let Err(err) = actions::edit_request(&conn, &user, request_id, &patch).await {
+                return Ok(action_error(err));
+            }
+            let detail = db::get_request_detail_by_id(&conn, request_id)
+                .await
+                .map_err(|_| INTERNAL_ERROR!())?;
+            let result = to_string(&detail).map_err(|_| INTERNAL_ERROR!())?;
+            Ok(OK!(result))
+        }
+        Some(comments) if comments == "comments" => {
+            let comment_id = match parse_id(components.next()) {
+                Some(comment_id) => comment_id,
+                None => return Ok(BAD_REQUEST!()),
+            };
+            if method == Method::DELETE {
+                return match actions::delete_comment(&conn, &user, request_id, comment_id).await {
+                    Ok(()) => Ok(HttpResponse::NoContent().finish()),
+                    Err(err) => Ok(action_error(err)),
+                };
+            }
+            let input =
+                serde_json::from_slice::<CommentInput>(&body).map_err(|_| BAD_REQUEST!())?;
+            if let Err(err) =
+                actions::edit_comment(&conn, &user, request_id, comment_id, &input).await
+            {
+                return Ok(action_error(err));
+            }
+            rest_comment(&conn, request_id, comment_id).await
+        }
+        _ => Ok(BAD_REQUEST!()),
+    }
+}
+
+#[inline]
+async fn rest_comment(
+    conn: &PgPool,
+    request_id: i64,
+    comment_id: i64,
+) -> Result<HttpResponse, Error> {
+    let comment = db::get_comment_by_id(conn, request_id, comment_id)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let result = to_string(&comment).map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(OK!(result))
+}
+
+#[inline]
+async fn rest_requests(pool: web::Data<PgPool>, req: &HttpRequest) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let filter = web::Query::<RequestFilter>::from_query(req.query_string())
+        .map_err(|_| BAD_REQUEST!())?
+        .into_inner();
+    if let Err(message) = filter.validate() {
+        return Ok(ERROR_MESSAGE!(BadRequest, message));
+    }
+    let (total, requests, next_cursor) = db::get_requests(&conn, &filter)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let result = to_string(&RequestsResponse {
+        success: true,
+        total,
+        next_cursor,
+        requests,
+    })
+    .map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(OK!(result))
+}
+
+#[inline]
+async fn rest_new_request(
     pool: web::Data<PgPool>,
-    _components: Iter<'_>,
+    user: Option<AuthenticatedUser>,
+    body: web::Bytes,
 ) -> Result<HttpResponse, Error> {
     let conn = pool.get_ref();
-    let requests = db::get_open_requests_json(&conn)
+    let user = match user {
+        Some(user) if user.allows(SCOPE_WRITE_REQUESTS) => user.0,
+        Some(_) => return Ok(missing_scope(SCOPE_WRITE_REQUESTS)),
+        None => return Ok(NOT_AUTHORIZED!()),
+    };
+    let input = serde_json::from_slice::<RequestInput>(&body).map_err(|_| BAD_REQUEST!())?;
+    if let Err(message) = input.validate() {
+        return Ok(ERROR_MESSAGE!(BadRequest, message));
+    }
+    let request = db::add_request(&conn, user.id, &input)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let result = to_string(&request).map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(HttpResponse::Created()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(result))
+}
+
+#[inline]
+async fn rest_search(pool: web::Data<PgPool>, req: &HttpRequest) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let query = web::Query::<SearchQuery>::from_query(req.query_string())
+        .map_err(|_| BAD_REQUEST!())?
+        .into_inner();
+    if query.q.trim().is_empty() {
+        return Ok(ERROR_MESSAGE!(BadRequest, "Search query must not be empty"));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let results = db::search_requests(&conn, &query.q, limit)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let result = to_string(&results).map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(OK!(result))
+}
+
+#[inline]
+fn parse_id(component: Option<&std::ffi::OsStr>) -> Option<i64> {
+    str::parse::<i64>(&component?.to_string_lossy()).ok()
+}
+
+async fn rest_plans(
+    pool: web::Data<PgPool>,
+    method: &Method,
+    user: Option<AuthenticatedUser>,
+    mut components: Iter<'_>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let plan_id = components.next();
+    if plan_id.is_none() {
+        return match method {
+            &Method::GET => {
+                let plans = db::get_plans(&conn).await.map_err(|_| INTERNAL_ERROR!())?;
+                let result = to_string(&plans).map_err(|_| INTERNAL_ERROR!())?;
+                Ok(OK!(result))
+            }
+            &Method::POST => {
+                if let Err(response) = require_admin(user.as_ref()) {
+                    return Ok(response);
+                }
+                let input =
+                    serde_json::from_slice::<IterPlanInput>(&body).map_err(|_| BAD_REQUEST!())?;
+                if let Err(message) = input.validate() {
+                    return Ok(ERROR_MESSAGE!(BadRequest, message));
+                }
+                let plan = db::add_plan(&conn, &input)
+                    .await
+                    .map_err(|_| INTERNAL_ERROR!())?;
+                let result = to_string(&plan).map_err(|_| INTERNAL_ERROR!())?;
+                Ok(HttpResponse::Created()
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(result))
+            }
+            _ => Ok(BAD_REQUEST!()),
+        };
+    }
+    let plan_id = match parse_id(plan_id) {
+        Some(plan_id) => plan_id,
+        None => return Ok(BAD_REQUEST!()),
+    };
+    match components.next() {
+        None => rest_plan(&conn, method, user, plan_id, body).await,
+        Some(entries) if entries == "entries" => {
+            let entry_id = match components.next() {
+                Some(entry_id) => match parse_id(Some(entry_id)) {
+                    Some(entry_id) => Some(entry_id),
+                    None => return Ok(BAD_REQUEST!()),
+                },
+                None => None,
+            };
+            rest_plan_entries(&conn, method, user, plan_id, entry_id, body).await
+        }
+        _ => Ok(BAD_REQUEST!()),
+    }
+}
+
+async fn rest_plan(
+    conn: &PgPool,
+    method: &Method,
+    user: Option<AuthenticatedUser>,
+    plan_id: i64,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    if method != Method::GET {
+        if let Err(response) = require_admin(user.as_ref()) {
+            return Ok(response);
+        }
+    }
+    let plan = match method {
+        &Method::GET => db::get_plan_by_id(conn, plan_id)
+            .await
+            .map_err(|_| INTERNAL_ERROR!())?,
+        &Method::PUT => {
+            let input =
+                serde_json::from_slice::<IterPlanInput>(&body).map_err(|_| BAD_REQUEST!())?;
+            if let Err(message) = input.validate() {
+                return Ok(ERROR_MESSAGE!(BadRequest, message));
+            }
+            db::update_plan(conn, plan_id, &input)
+                .await
+                .map_err(|_| INTERNAL_ERROR!())?
+        }
+        &Method::DELETE => {
+            let deleted = db::delete_plan(conn, plan_id)
+                .await
+                .map_err(|_| INTERNAL_ERROR!())?;
+            if deleted {
+                return Ok(HttpResponse::NoContent().finish());
+            }
+            None
+        }
+        _ => return Ok(BAD_REQUEST!()),
+    };
+    if let Some(plan) = plan {
+        let result = to_string(&plan).map_err(|_| INTERNAL_ERROR!())?;
+        return Ok(OK!(result));
+    }
+
+    Ok(ERROR_MESSAGE!(NotFound, "Plan not found"))
+}
+
+async fn rest_plan_entries(
+    conn: &PgPool,
+    method: &Method,
+    user: Option<AuthenticatedUser>,
+    plan_id: i64,
+    entry_id: Option<i64>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let plan = db::get_plan_by_id(conn, plan_id)
         .await
         .map_err(|_| INTERNAL_ERROR!())?;
+    if plan.is_none() {
+        return Ok(ERROR_MESSAGE!(NotFound, "Plan not found"));
+    }
+    if method == Method::GET && entry_id.is_none() {
+        let entries = db::get_plan_entry_tree(conn, plan_id)
+            .await
+            .map_err(|_| INTERNAL_ERROR!())?;
+        let result = to_string(&entries).map_err(|_| INTERNAL_ERROR!())?;
+        return Ok(OK!(result));
+    }
+    if let Err(response) = require_admin(user.as_ref()) {
+        return Ok(response);
+    }
+    let entry = match (method, entry_id) {
+        (&Method::POST, None) => {
+            let input =
+                serde_json::from_slice::<IterEntryInput>(&body).map_err(|_| BAD_REQUEST!())?;
+            if let Err(message) = validate_entry(conn, plan_id, None, &input).await? {
+                return Ok(ERROR_MESSAGE!(BadRequest, message));
+            }
+            let entry = match db::add_entry(conn, plan_id, &input).await {
+                Ok(entry) => entry,
+                Err(err) => return Ok(entry_write_error(err)),
+            };
+            let result = to_string(&entry).map_err(|_| INTERNAL_ERROR!())?;
+            return Ok(HttpResponse::Created()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(result));
+        }
+        (&Method::PUT, Some(entry_id)) => {
+            let input =
+                serde_json::from_slice::<IterEntryInput>(&body).map_err(|_| BAD_REQUEST!())?;
+            if let Err(message) = validate_entry(conn, plan_id, Some(entry_id), &input).await? {
+                return Ok(ERROR_MESSAGE!(BadRequest, message));
+            }
+            match db::update_entry(conn, plan_id, entry_id, &input).await {
+                Ok(entry) => entry,
+                Err(err) => return Ok(entry_write_error(err)),
+            }
+        }
+        (&Method::DELETE, Some(entry_id)) => {
+            let deleted = db::delete_entry(conn, plan_id, entry_id)
+                .await
+                .map_err(|_| INTERNAL_ERROR!())?;
+            if deleted {
+                return Ok(HttpResponse::NoContent().finish());
+            }
+            None
+        }
+        _ => return Ok(BAD_REQUEST!()),
+    };
+    if let Some(entry) = entry {
+        let result = to_string(&entry).map_err(|_| INTERNAL_ERROR!())?;
+        return Ok(OK!(result));
+    }
+
+    Ok(ERROR_MESSAGE!(NotFound, "Entry not found"))
+}
+
+fn entry_write_error(err: anyhow::Error) -> HttpResponse {
+    if let Some(err) = err.downcast_ref::<db::EntryDateError>() {
+        let result = serde_json::json!({
+            "success": false,
+            "message": err.to_string(),
+            "begin_date": err.begin_date,
+            "end_date": err.end_date,
+        });
+        return HttpResponse::UnprocessableEntity()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(result.to_string());
+    }
 
-    Ok(OK!(requests))
+    INTERNAL_ERROR!()
+}
+
+/// Checks the entry fields and that its parent belongs to the same plan without forming a cycle
+async fn validate_entry(
+    conn: &PgPool,
+    plan_id: i64,
+    entry_id: Option<i64>,
+    input: &IterEntryInput,
+) -> Result<Result<(), &'static str>, Error> {
+    if let Err(message) = input.validate() {
+        return Ok(Err(message));
+    }
+    if let Some(parent_id) = input.parent_id {
+        let parent = db::get_entry_by_id(conn, parent_id)
+            .await
+            .map_err(|_| INTERNAL_ERROR!())?;
+        match parent {
+            Some(parent) if parent.plan_id == plan_id => (),
+            _ => return Ok(Err("Parent entry does not exist in this plan")),
+        }
+        if let Some(entry_id) = entry_id {
+            let is_cycle = db::is_entry_ancestor(conn, entry_id, parent_id)
+                .await
+                .map_err(|_| INTERNAL_ERROR!())?;
+            if is_cycle {
+                return Ok(Err("Entry cannot be nested under itself"));
+            }
+        }
+    }
+
+    Ok(Ok(()))
 }
 
 #[inline]
-async fn rest_login(pool: web::Data<PgPool>, req: &HttpRequest) -> Result<HttpResponse, Error> {
+async fn rest_register(pool: web::Data<PgPool>, body: web::Bytes) -> Result<HttpResponse, Error> {
+    let input = serde_json::from_slice::<RegisterInput>(&body).map_err(|_| BAD_REQUEST!())?;
+    let user = match auth::register_user(pool.get_ref(), &input, None).await {
+        Ok(user) => user,
+        Err(err) => return Ok(action_error(err)),
+    };
+    let result = serde_json::json!({
+        "success": true,
+        "username": user.username,
+        "approved": user.approved,
+    });
+
+    Ok(HttpResponse::Created()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(result.to_string()))
+}
+
+#[inline]
+async fn rest_login(
+    pool: web::Data<PgPool>,
+    keys: web::Data<JwtKeys>,
+    req: &HttpRequest,
+) -> Result<HttpResponse, Error> {
     let headers = req.headers();
     let username = headers.get("x-username");
     let password = headers.get("x-password");
@@ -133,58 +701,236 @@ async fn rest_login(pool: web::Data<PgPool>, req: &HttpRequest) -> Result<HttpRe
     }
     if let Some(password) = password {
         let password = password.to_str().map_err(|_| NOT_AUTHORIZED!())?;
-        let is_valid_password = auth::check_password(pool, username_str.to_string(), password)
-            .await
-            .map_err(|_| INTERNAL_ERROR!())?;
-        if is_valid_password {
-            let token = issue_jwt_token(username_str)
+        let is_valid_password =
+            auth::check_password(pool.clone(), username_str.to_string(), password)
                 .await
                 .map_err(|_| INTERNAL_ERROR!())?;
-            let result = to_string(&TokenResponse {
-                success: true,
-                token,
-            })
-            .map_err(|_| INTERNAL_ERROR!())?;
-            return Ok(OK!(result));
+        if is_valid_password {
+            return issue_tokens(pool.get_ref(), keys, username_str).await;
         }
     }
 
     Ok(NOT_AUTHORIZED!())
 }
 
+/// Trades a refresh token for a new JWT and refresh token
+#[inline]
+async fn rest_token_refresh(
+    pool: web::Data<PgPool>,
+    keys: web::Data<JwtKeys>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let input = serde_json::from_slice::<RefreshInput>(&body).map_err(|_| BAD_REQUEST!())?;
+    let username = db::take_refresh_token(&conn, &token_hash(&input.refresh_token))
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let user = match username {
+        Some(username) => db::get_user_by_username(&conn, &username)
+            .await
+            .map_err(|_| INTERNAL_ERROR!())?,
+        None => return Ok(NOT_AUTHORIZED!()),
+    };
+    if user.locked || !user.approved {
+        return Ok(NOT_AUTHORIZED!());
+    }
+
+    issue_tokens(&conn, keys, &user.username).await
+}
+
+/// Revokes the JWT the request is made with, along with its refresh token
+#[inline]
+async fn rest_logout(
+    pool: web::Data<PgPool>,
+    keys: web::Data<JwtKeys>,
+    req: &HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let conn = pool.get_ref();
+    let token = match bearer_token(req) {
+        Some(token) if token.starts_with(TOKEN_PREFIX) => {
+            return Ok(ERROR_MESSAGE!(
+                BadRequest,
+                "API tokens are revoked on the account page"
+            ))
+        }
+        Some(token) => token,
+        None => return Ok(NOT_AUTHORIZED!()),
+    };
+    let claims = match validate_jwt_token(&conn, keys, token).await {
+        Ok(claims) => claims,
+        Err(_) => return Ok(NOT_AUTHORIZED!()),
+    };
+    db::revoke_jwt(&conn, &claims.jti, claims.exp)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(r#"{"success": true}"#))
+}
+
 // utility functions
+fn action_error(err: ActionError) -> HttpResponse {
+    match err {
+        ActionError::NotFound(_) => ERROR_MESSAGE!(NotFound, err.to_string()),
+        ActionError::Forbidden(_) => ERROR_MESSAGE!(Forbidden, err.to_string()),
+        ActionError::Conflict(_) => ERROR_MESSAGE!(Conflict, err.to_string()),
+        ActionError::Invalid(_) => ERROR_MESSAGE!(BadRequest, err.to_string()),
+        ActionError::Internal(_) => INTERNAL_ERROR!(),
+    }
+}
+
+/// Plans and their entries can only be changed by admins, through tokens with the admin scope
+fn require_admin(user: Option<&AuthenticatedUser>) -> Result<(), HttpResponse> {
+    match user {
+        Some(user) if user.0.admin => Ok(()),
+        Some(user) if !user.allows(SCOPE_ADMIN) => Err(missing_scope(SCOPE_ADMIN)),
+        Some(_) => Err(ERROR_MESSAGE!(Forbidden, "Only admins can change plans")),
+        None => Err(NOT_AUTHORIZED!()),
+    }
+}
+
 #[inline]
-async fn issue_jwt_token(username: &str) -> Result<String, Error> {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET not set");
-    let expiration = Duration::days(1);
+fn missing_scope(scope: &str) -> HttpResponse {
+    ERROR_MESSAGE!(Forbidden, format!("This token lacks the {} scope", scope))
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| token.to_owned())
+}
+
+/// Issues a JWT along with a refresh token, which can be traded for a new pair once
+async fn issue_tokens(
+    conn: &PgPool,
+    keys: web::Data<JwtKeys>,
+    username: &str,
+) -> Result<HttpResponse, Error> {
+    let jti = random_token();
+    let token = issue_jwt_token(keys, username, &jti)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    let refresh_token = random_token();
+    let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_DAYS);
+    db::add_refresh_token(
+        conn,
+        &token_hash(&refresh_token),
+        username,
+        &jti,
+        expires_at,
+    )
+    .await
+    .map_err(|_| INTERNAL_ERROR!())?;
+    let result = to_string(&TokenResponse {
+        success: true,
+        token,
+        refresh_token,
+    })
+    .map_err(|_| INTERNAL_ERROR!())?;
+
+    Ok(OK!(result))
+}
+
+#[inline]
+async fn issue_jwt_token(
+    keys: web::Data<JwtKeys>,
+    username: &str,
+    jti: &str,
+) -> Result<String, Error> {
+    let expiration = Duration::days(ACCESS_TOKEN_DAYS);
     let claim = UserClaims {
         iat: Utc::now(),
         nbf: Utc::now(),
         sub: username.to_owned(),
+        jti: jti.to_owned(),
         exp: Utc::now() + expiration,
     };
-    let token = web::block(move || {
-        encode(
-            &Header::default(),
-            &claim,
-            &EncodingKey::from_secret(secret.as_ref()),
-        )
-    })
-    .await?;
+    let token = web::block(move || keys.encode(&claim))
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
 
     Ok(token)
 }
 
-async fn validate_jwt_token(token: String) -> Result<String, Error> {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET not set");
-    let token_data = web::block(move || {
-        decode::<UserClaims>(
-            &token,
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::default(),
-        )
-    })
-    .await?;
+/// Decodes a JWT, rejecting it if it has been revoked
+async fn validate_jwt_token(
+    conn: &PgPool,
+    keys: web::Data<JwtKeys>,
+    token: String,
+) -> Result<UserClaims, Error> {
+    let token_data = web::block(move || keys.decode::<UserClaims>(&token))
+        .await
+        .map_err(|_| NOT_AUTHORIZED!())?;
+    let revoked = db::is_jwt_revoked(conn, &token_data.claims.jti)
+        .await
+        .map_err(|_| INTERNAL_ERROR!())?;
+    if revoked {
+        return Err(Error::from(NOT_AUTHORIZED!()));
+    }
+
+    Ok(token_data.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokens::SCOPE_READ;
 
-    Ok(token_data.claims.sub)
+    fn user(admin: bool) -> User {
+        User {
+            id: 1,
+            username: "tester".to_string(),
+            admin,
+            password_hash: None,
+            locked: false,
+            approved: true,
+        }
+    }
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    }
+
+    #[test]
+    fn admin_scope_keeps_admin() {
+        let user = AuthenticatedUser::new(user(true), scopes(ALL_SCOPES));
+        assert!(user.0.admin);
+    }
+
+    #[test]
+    fn missing_admin_scope_drops_admin() {
+        let user = AuthenticatedUser::new(user(true), scopes(&[SCOPE_READ, SCOPE_WRITE_REQUESTS]));
+        assert!(!user.0.admin);
+        assert!(user.allows(SCOPE_WRITE_REQUESTS));
+    }
+
+    #[actix_rt::test]
+    async fn scoped_token_cannot_use_admin_override() {
+        // Reassigning is refused before the database is touched
+        let pool = PgPool::connect_lazy("postgres://localhost/pakreq").unwrap();
+        let user = AuthenticatedUser::new(user(true), scopes(&[SCOPE_READ, SCOPE_WRITE_REQUESTS]));
+        let err = actions::assign_request(&pool, &user.0, 1, "someone")
+            .await
+            .unwrap_err();
+        assert_eq!(action_error(err).status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn scoped_token_cannot_change_plans() {
+        let user = AuthenticatedUser::new(user(true), scopes(&[SCOPE_READ, SCOPE_WRITE_REQUESTS]));
+        let response = require_admin(Some(&user)).unwrap_err();
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn non_admin_cannot_change_plan_entries() {
+        let user = AuthenticatedUser::new(user(false), scopes(ALL_SCOPES));
+        let response = require_admin(Some(&user)).unwrap_err();
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+        let response = require_admin(None).unwrap_err();
+        assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    }
 }
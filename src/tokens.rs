@@ -0,0 +1,35 @@
+//! Personal access tokens, for bots and scripts using the REST API
+use rand::RngCore;
+use ring::digest;
+
+/// Marks API tokens, so that they are not mistaken for JWTs
+pub const TOKEN_PREFIX: &str = "pkr_";
+
+/// Reading through the API, as the owner of the token
+pub const SCOPE_READ: &str = "read";
+/// Creating, editing and commenting on requests
+pub const SCOPE_WRITE_REQUESTS: &str = "write:requests";
+/// Everything the owner can do as an admin
+pub const SCOPE_ADMIN: &str = "admin";
+
+/// Granted to password logins
+pub const ALL_SCOPES: &[&str] = &[SCOPE_READ, SCOPE_WRITE_REQUESTS, SCOPE_ADMIN];
+
+/// Generates a new token, which is shown to the user once and never stored
+pub fn generate_token() -> String {
+    format!("{}{}", TOKEN_PREFIX, random_token())
+}
+
+/// 32 random bytes, encoded as URL-safe base64
+pub fn random_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+
+    base64::encode_config(&token, base64::URL_SAFE_NO_PAD)
+}
+
+/// The hash stored in the database in place of the token
+#[inline]
+pub fn token_hash(token: &str) -> String {
+    hex::encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}